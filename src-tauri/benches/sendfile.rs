@@ -0,0 +1,210 @@
+// 比较按帧复制发送和 sendfile 发送同一个文件时发送线程的 CPU 时间。
+// buffered 与压缩或加密会话的发送路径一样读出每个数据块、计算 SHA-256 并复制成帧；
+// sendfile 不读出数据，整体校验和在发送前已经随清单算好，不计入发送开销
+//
+//     cargo bench --bench sendfile
+//
+// 通过环境变量调整: BENCH_FILE 使用已有文件（默认生成临时文件），
+// BENCH_SIZE_MB 临时文件大小，BENCH_CHUNK_KB 每帧大小，BENCH_ROUNDS 每种方式的轮数。
+// 在文件服务器上测试时可以把 BENCH_TARGET 设为另一台机器上 `nc -l <port> > /dev/null` 的地址。
+
+#[cfg(target_os = "linux")]
+fn main() {
+    bench::run();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("sendfile is only used on Linux, nothing to compare");
+}
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use sha2::{Digest, Sha256};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::AsRawFd;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // 与 protocol.rs 中 Data 帧的帧头和负载头长度一致
+    const HEADER_LEN: usize = 5 + 12;
+
+    fn env_usize(name: &str, default: usize) -> usize {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    // 当前线程的用户态和内核态 CPU 时间
+    fn thread_cpu() -> (Duration, Duration) {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+        let time = |tv: libc::timeval| {
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+        };
+        (time(usage.ru_utime), time(usage.ru_stime))
+    }
+
+    fn header(offset: u64, len: usize) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = 4;
+        header[1..5].copy_from_slice(&((12 + len) as u32).to_be_bytes());
+        header[9..17].copy_from_slice(&offset.to_be_bytes());
+        header
+    }
+
+    // protocol.rs 中 Frame::data 和 write_frame 的方式: 数据块先复制成帧的负载，
+    // 再与帧头合并复制到写入缓冲区，最后写入套接字
+    fn send_buffered(file: &mut File, socket: &mut TcpStream, chunk: usize) -> io::Result<u64> {
+        let mut buffer = vec![0; chunk];
+        let mut hasher = Sha256::new();
+        let mut offset = 0u64;
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                std::hint::black_box(hasher.finalize());
+                return Ok(offset);
+            }
+            hasher.update(&buffer[..n]);
+            let header = header(offset, n);
+            let mut payload = Vec::with_capacity(HEADER_LEN - 5 + n);
+            payload.extend_from_slice(&header[5..]);
+            payload.extend_from_slice(&buffer[..n]);
+            let mut frame = Vec::with_capacity(5 + payload.len());
+            frame.extend_from_slice(&header[..5]);
+            frame.extend_from_slice(&payload);
+            socket.write_all(&frame)?;
+            offset += n as u64;
+        }
+    }
+
+    // sendfile.rs 的方式: 帧头用 MSG_MORE 发送，数据由内核从页缓存发送，不经过用户态
+    fn send_sendfile(file: &mut File, socket: &mut TcpStream, chunk: usize) -> io::Result<u64> {
+        let size = file.metadata()?.len();
+        let mut offset = 0u64;
+        while offset < size {
+            let n = (size - offset).min(chunk as u64) as usize;
+            let header = header(offset, n);
+            let sent = unsafe {
+                libc::send(
+                    socket.as_raw_fd(),
+                    header.as_ptr().cast(),
+                    header.len(),
+                    libc::MSG_MORE | libc::MSG_NOSIGNAL,
+                )
+            };
+            if sent != header.len() as isize {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut position = offset as libc::off_t;
+            let end = offset + n as u64;
+            while (position as u64) < end {
+                let count = (end - position as u64) as usize;
+                let sent = unsafe {
+                    libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut position, count)
+                };
+                if sent <= 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            offset = end;
+        }
+        Ok(offset)
+    }
+
+    // 本机测试时在另一个线程丢弃收到的数据
+    fn local_sink() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind sink");
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    let _ = io::copy(&mut &stream, &mut io::sink());
+                });
+            }
+        });
+        addr
+    }
+
+    fn prepare_file() -> (PathBuf, bool) {
+        if let Ok(path) = env::var("BENCH_FILE") {
+            return (PathBuf::from(path), false);
+        }
+
+        let size = env_usize("BENCH_SIZE_MB", 512) * 1024 * 1024;
+        let path = env::temp_dir().join(format!("lantransfer-bench-{}", std::process::id()));
+        let mut file = File::create(&path).expect("Failed to create benchmark file");
+        let block: Vec<u8> = (0..1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+        let mut written = 0;
+        while written < size {
+            let len = block.len().min(size - written);
+            file.write_all(&block[..len]).unwrap();
+            written += len;
+        }
+        (path, true)
+    }
+
+    pub fn run() {
+        let chunk = env_usize("BENCH_CHUNK_KB", 1024) * 1024;
+        let rounds = env_usize("BENCH_ROUNDS", 3).max(1);
+        let target = env::var("BENCH_TARGET").unwrap_or_else(|_| local_sink());
+        let (path, temporary) = prepare_file();
+
+        // 先读一遍，两种方式都从页缓存读取
+        let mut warmup = File::open(&path).expect("Failed to open benchmark file");
+        let size = io::copy(&mut warmup, &mut io::sink()).unwrap();
+        println!(
+            "file {} ({} MiB), chunk {} KiB, target {}",
+            path.display(),
+            size / 1024 / 1024,
+            chunk / 1024,
+            target
+        );
+
+        type Sender = fn(&mut File, &mut TcpStream, usize) -> io::Result<u64>;
+        let modes: [(&str, Sender); 2] = [("buffered", send_buffered), ("sendfile", send_sendfile)];
+        let mut cpu_per_gib = Vec::new();
+        for (name, send) in modes {
+            let mut wall = Duration::ZERO;
+            let mut user = Duration::ZERO;
+            let mut system = Duration::ZERO;
+            for _ in 0..rounds {
+                let mut file = File::open(&path).unwrap();
+                let mut socket = TcpStream::connect(&target).expect("Failed to connect");
+                let (user_start, system_start) = thread_cpu();
+                let started = Instant::now();
+                send(&mut file, &mut socket, chunk).expect("Send failed");
+                wall += started.elapsed();
+                let (user_end, system_end) = thread_cpu();
+                user += user_end - user_start;
+                system += system_end - system_start;
+            }
+
+            let gib = (size * rounds as u64) as f64 / (1024.0 * 1024.0 * 1024.0);
+            let cpu = (user + system).as_secs_f64();
+            println!(
+                "{:>9}: {:>8.1} MiB/s, CPU {:.3} s/GiB (user {:.3}, sys {:.3})",
+                name,
+                gib * 1024.0 / wall.as_secs_f64(),
+                cpu / gib,
+                user.as_secs_f64() / gib,
+                system.as_secs_f64() / gib
+            );
+            cpu_per_gib.push(cpu / gib);
+        }
+        println!(
+            "sendfile uses {:.0}% less CPU per GiB than buffered",
+            (1.0 - cpu_per_gib[1] / cpu_per_gib[0]) * 100.0
+        );
+
+        if temporary {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
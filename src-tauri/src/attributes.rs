@@ -0,0 +1,178 @@
+use crate::manifest::FileEntry;
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+// 权限位，不含 setuid、setgid 和粘滞位，非 Unix 系统为 None
+pub fn mode(metadata: &Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+// 读取 user 命名空间的扩展属性，值用 base64 编码，没有时返回 None
+pub fn read_xattrs(path: &Path) -> Option<BTreeMap<String, String>> {
+    #[cfg(target_os = "linux")]
+    {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        let mut xattrs = BTreeMap::new();
+        let names = match linux::list_xattrs(path) {
+            Ok(names) => names,
+            Err(e) => {
+                log::warn!("Failed to list xattrs of {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        for name in names
+            .into_iter()
+            .filter(|name| name.starts_with(XATTR_PREFIX))
+        {
+            match linux::get_xattr(path, &name) {
+                Ok(value) => {
+                    xattrs.insert(name, STANDARD.encode(value));
+                }
+                Err(e) => log::warn!("Failed to read xattr {} of {}: {}", name, path.display(), e),
+            }
+        }
+        (!xattrs.is_empty()).then_some(xattrs)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+// 文件落盘改名之后调用，按清单恢复扩展属性、修改时间和权限位，目录不处理。
+// 修改权限放在最后，去掉写权限后仍能设置前两项
+pub fn apply(path: &Path, entry: &FileEntry, xattrs: bool) -> Result<(), String> {
+    if xattrs {
+        if let Some(values) = &entry.xattrs {
+            write_xattrs(path, values)?;
+        }
+    }
+
+    if let Some(modified) = entry.modified {
+        let time = match u64::try_from(modified) {
+            Ok(millis) => UNIX_EPOCH + Duration::from_millis(millis),
+            Err(_) => UNIX_EPOCH - Duration::from_millis(modified.unsigned_abs()),
+        };
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(time))
+            .map_err(|e| format!("Failed to set modification time: {}", e))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const XATTR_PREFIX: &str = "user.";
+
+fn write_xattrs(path: &Path, values: &BTreeMap<String, String>) -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        // 其他命名空间需要特权，也可能影响系统的安全策略
+        for (name, value) in values
+            .iter()
+            .filter(|(name, _)| name.starts_with(XATTR_PREFIX))
+        {
+            let value = STANDARD
+                .decode(value)
+                .map_err(|e| format!("Invalid value for xattr {}: {}", name, e))?;
+            linux::set_xattr(path, name, &value)
+                .map_err(|e| format!("Failed to set xattr {}: {}", name, e))?;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (path, values);
+    Ok(())
+}
+
+// libc 的扩展属性接口
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn c_name(name: &str) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    // 先取长度再读取，两次调用之间属性变化时返回 ERANGE
+    fn read_sized(mut read: impl FnMut(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        let len = read(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; len as usize];
+        let len = read(buffer.as_mut_ptr(), buffer.len());
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buffer.truncate(len as usize);
+        Ok(buffer)
+    }
+
+    pub fn list_xattrs(path: &Path) -> io::Result<Vec<String>> {
+        let path = c_path(path)?;
+        let names = read_sized(|buffer, len| unsafe {
+            libc::listxattr(path.as_ptr(), buffer.cast(), len)
+        })?;
+        Ok(names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .collect())
+    }
+
+    pub fn get_xattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+        let path = c_path(path)?;
+        let name = c_name(name)?;
+        read_sized(|buffer, len| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), len)
+        })
+    }
+
+    pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_path(path)?;
+        let name = c_name(name)?;
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use crate::compression::{Codec, Compressor};
+use crate::protocol::{Frame, FrameType, MAX_FRAME_LEN};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+
+// 不超过该大小的文件打包发送，双方按同样的规则判断
+pub const BATCH_FILE_SIZE: u64 = 64 * 1024;
+// 每个包最多包含的文件数，文件内容总量不超过协商的块大小
+const BATCH_MAX_FILES: usize = 1024;
+// 每个文件的记录头: 4 字节文件序号 + 8 字节大小 + 32 字节 SHA-256
+const RECORD_HEADER_LEN: usize = 4 + 8 + 32;
+// Batch 帧负载第一个字节，压缩时之后是 4 字节原始长度
+const FLAG_PLAIN: u8 = 0;
+const FLAG_COMPRESSED: u8 = 1;
+
+// 打好的一个包
+pub struct PackedBatch {
+    pub frame: Frame,
+    // 包内文件在清单中的序号，按顺序排列
+    pub files: Vec<u32>,
+    // 文件内容的字节数和在连接上实际占用的字节数
+    pub bytes: u64,
+    pub wire_bytes: u64,
+}
+
+// 发送方把连续的小文件依次写成记录，攒够一个块后作为一个 Batch 帧发送
+pub struct BatchWriter {
+    body: Vec<u8>,
+    files: Vec<u32>,
+    bytes: u64,
+    limit: usize,
+    compressor: Option<Compressor>,
+}
+
+impl BatchWriter {
+    pub fn new(limit: usize, codec: Option<Codec>) -> Self {
+        Self {
+            body: Vec::new(),
+            files: Vec::new(),
+            bytes: 0,
+            limit,
+            compressor: codec.map(Compressor::for_stream),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.files.len() >= BATCH_MAX_FILES || self.body.len() >= self.limit
+    }
+
+    pub fn push(&mut self, index: u32, data: &[u8]) {
+        self.body.extend_from_slice(&index.to_be_bytes());
+        self.body
+            .extend_from_slice(&(data.len() as u64).to_be_bytes());
+        self.body.extend_from_slice(&Sha256::digest(data));
+        self.body.extend_from_slice(data);
+        self.files.push(index);
+        self.bytes += data.len() as u64;
+    }
+
+    // 取出已打包的文件，整个包一起压缩，压缩不划算时原样发送
+    pub fn take(&mut self) -> PackedBatch {
+        let body = std::mem::take(&mut self.body);
+        let files = std::mem::take(&mut self.files);
+        let bytes = std::mem::take(&mut self.bytes);
+
+        let compressed = self
+            .compressor
+            .as_mut()
+            .and_then(|compressor| compressor.compress(&body));
+        let (payload, wire_bytes) = match compressed {
+            Some(compressed) => {
+                let mut payload = Vec::with_capacity(5 + compressed.len());
+                payload.push(FLAG_COMPRESSED);
+                payload.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                payload.extend_from_slice(&compressed);
+                // 记录头的开销按比例分摊，只统计文件内容
+                let wire = bytes * compressed.len() as u64 / body.len().max(1) as u64;
+                (payload, wire)
+            }
+            None => {
+                let mut payload = Vec::with_capacity(1 + body.len());
+                payload.push(FLAG_PLAIN);
+                payload.extend_from_slice(&body);
+                (payload, bytes)
+            }
+        };
+
+        PackedBatch {
+            frame: Frame::new(FrameType::Batch, payload),
+            files,
+            bytes,
+            wire_bytes,
+        }
+    }
+}
+
+// 从包中解出的一个文件
+pub struct BatchFile {
+    pub index: u32,
+    pub sha256: String,
+    pub data: Vec<u8>,
+}
+
+// 解开一个 Batch 帧，返回其中的文件和文件内容在连接上实际占用的字节数
+pub fn unpack(frame: &Frame, codec: Option<Codec>) -> Result<(VecDeque<BatchFile>, u64), String> {
+    let (&flag, rest) = frame.payload.split_first().ok_or("Batch frame too short")?;
+    let decompressed;
+    let body = match flag {
+        FLAG_PLAIN => rest,
+        FLAG_COMPRESSED => {
+            let codec = codec.ok_or("Peer sent compressed data without negotiating compression")?;
+            if rest.len() < 4 {
+                return Err("Batch frame too short".to_string());
+            }
+            let (raw_len, compressed) = rest.split_at(4);
+            let raw_len = u32::from_be_bytes([raw_len[0], raw_len[1], raw_len[2], raw_len[3]]);
+            if raw_len as usize > MAX_FRAME_LEN {
+                return Err(format!("Batch too large: {} bytes", raw_len));
+            }
+            decompressed = codec.decompress(compressed, raw_len as usize)?;
+            &decompressed[..]
+        }
+        _ => return Err(format!("Unknown batch format {}", flag)),
+    };
+
+    let mut files = VecDeque::new();
+    let mut bytes = 0u64;
+    let mut rest = body;
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_LEN {
+            return Err("Truncated batch record".to_string());
+        }
+        let (header, tail) = rest.split_at(RECORD_HEADER_LEN);
+        let mut index = [0u8; 4];
+        let mut size = [0u8; 8];
+        index.copy_from_slice(&header[..4]);
+        size.copy_from_slice(&header[4..12]);
+        let size = u64::from_be_bytes(size);
+        if size > tail.len() as u64 {
+            return Err("Truncated batch record".to_string());
+        }
+
+        let (data, tail) = tail.split_at(size as usize);
+        files.push_back(BatchFile {
+            index: u32::from_be_bytes(index),
+            sha256: header[12..]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            data: data.to_vec(),
+        });
+        bytes += size;
+        rest = tail;
+    }
+
+    // 与发送方相同，压缩后的大小按比例分摊到文件内容
+    let wire_bytes = if flag == FLAG_COMPRESSED {
+        bytes * (frame.payload.len() - 5) as u64 / body.len().max(1) as u64
+    } else {
+        bytes
+    };
+    Ok((files, wire_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files() -> Vec<(u32, Vec<u8>)> {
+        vec![
+            (3, b"hello".to_vec()),
+            (4, Vec::new()),
+            (9, "a fairly repetitive line\n".repeat(200).into_bytes()),
+        ]
+    }
+
+    fn round_trip(codec: Option<Codec>) -> PackedBatch {
+        let mut writer = BatchWriter::new(1024 * 1024, codec);
+        assert!(writer.is_empty());
+        for (index, data) in files() {
+            writer.push(index, &data);
+        }
+        let packed = writer.take();
+        assert!(writer.is_empty());
+        assert_eq!(packed.files, vec![3, 4, 9]);
+
+        let (unpacked, wire_bytes) = unpack(&packed.frame, codec).unwrap();
+        assert_eq!(wire_bytes, packed.wire_bytes);
+        assert_eq!(unpacked.len(), 3);
+        for (file, (index, data)) in unpacked.iter().zip(files()) {
+            assert_eq!(file.index, index);
+            assert_eq!(file.data, data);
+            assert_eq!(file.sha256, format!("{:x}", Sha256::digest(&data)));
+        }
+        packed
+    }
+
+    #[test]
+    fn plain_batch_round_trip() {
+        let packed = round_trip(None);
+        assert_eq!(packed.frame.payload[0], FLAG_PLAIN);
+        assert_eq!(packed.bytes, packed.wire_bytes);
+    }
+
+    #[test]
+    fn compressed_batch_round_trip() {
+        let packed = round_trip(Some(Codec::Zstd));
+        assert_eq!(packed.frame.payload[0], FLAG_COMPRESSED);
+        assert!(packed.wire_bytes < packed.bytes);
+    }
+
+    #[test]
+    fn rejects_truncated_batches() {
+        let mut writer = BatchWriter::new(1024 * 1024, None);
+        writer.push(1, b"some file content");
+        let mut frame = writer.take().frame;
+        frame.payload.truncate(frame.payload.len() - 1);
+        assert!(unpack(&frame, None).is_err());
+
+        frame.payload.truncate(10);
+        assert!(unpack(&frame, None).is_err());
+    }
+}
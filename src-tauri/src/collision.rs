@@ -0,0 +1,139 @@
+use crate::manifest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// 接收的文件与已有文件重名时的处理方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    // 改名为 "name (1).ext"
+    #[default]
+    Rename,
+    Overwrite,
+    Skip,
+    // 仅当收到的文件更新时才覆盖
+    KeepNewer,
+}
+
+// 为收到的文件决定最终保存路径，返回 None 表示跳过该文件
+// reserved 记录本批次已占用的路径，避免同一批次内的文件互相覆盖
+pub fn resolve_target(
+    path: &Path,
+    policy: CollisionPolicy,
+    incoming_modified: Option<i64>,
+    reserved: &mut HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    let exists = path.symlink_metadata().is_ok() || reserved.contains(path);
+    if !exists {
+        reserved.insert(path.to_path_buf());
+        return Some(path.to_path_buf());
+    }
+
+    let target = match policy {
+        CollisionPolicy::Rename => renamed_path(path, reserved),
+        CollisionPolicy::Overwrite => path.to_path_buf(),
+        CollisionPolicy::Skip => return None,
+        CollisionPolicy::KeepNewer => match (incoming_modified, existing_modified(path)) {
+            (Some(incoming), Some(existing)) if incoming > existing => path.to_path_buf(),
+            (Some(_), Some(_)) => return None,
+            // 无法比较修改时间时保留两份
+            _ => renamed_path(path, reserved),
+        },
+    };
+
+    reserved.insert(target.clone());
+    Some(target)
+}
+
+fn renamed_path(path: &Path, reserved: &HashSet<PathBuf>) -> PathBuf {
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut counter = 1;
+    loop {
+        let candidate = parent.join(format!("{} ({}){}", stem, counter, extension));
+        if candidate.symlink_metadata().is_err() && !reserved.contains(&candidate) {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+fn existing_modified(path: &Path) -> Option<i64> {
+    manifest::modified_millis(&path.metadata().ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "lantransfer-collision-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn rename_numbers_past_existing_and_reserved_names() {
+        let root = temp_root("rename");
+        let path = root.join("photo.jpg");
+        fs::write(&path, b"old").unwrap();
+        fs::write(root.join("photo (1).jpg"), b"old").unwrap();
+
+        let mut reserved = HashSet::new();
+        let first = resolve_target(&path, CollisionPolicy::Rename, None, &mut reserved);
+        assert_eq!(first, Some(root.join("photo (2).jpg")));
+        // 同一批次的第二个同名文件不会占用刚分配的名字
+        let second = resolve_target(&path, CollisionPolicy::Rename, None, &mut reserved);
+        assert_eq!(second, Some(root.join("photo (3).jpg")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rename_within_one_batch_and_without_extension() {
+        let root = temp_root("batch");
+        let path = root.join("README");
+
+        let mut reserved = HashSet::new();
+        let first = resolve_target(&path, CollisionPolicy::Rename, None, &mut reserved);
+        assert_eq!(first, Some(path.clone()));
+        let second = resolve_target(&path, CollisionPolicy::Rename, None, &mut reserved);
+        assert_eq!(second, Some(root.join("README (1)")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn overwrite_and_skip_existing_files() {
+        let root = temp_root("policies");
+        let path = root.join("notes.txt");
+        fs::write(&path, b"old").unwrap();
+
+        let mut reserved = HashSet::new();
+        assert_eq!(
+            resolve_target(&path, CollisionPolicy::Overwrite, None, &mut reserved),
+            Some(path.clone())
+        );
+        assert_eq!(
+            resolve_target(&path, CollisionPolicy::Skip, None, &mut HashSet::new()),
+            None
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::protocol::SessionParams;
+use std::path::Path;
+
+// zstd 的压缩级别，局域网上速度比压缩率更重要
+const ZSTD_LEVEL: i32 = 1;
+// 判断是否值得压缩时先压缩的样本大小
+const SAMPLE_LEN: usize = 64 * 1024;
+// 压缩后至少节省 1/MIN_SAVING 才发送压缩数据，否则对端解压不划算
+const MIN_SAVING: usize = 16;
+// 样本压缩效果不好时，之后这么多个数据块直接发送，再重新取样
+const IDLE_CHUNKS: u32 = 64;
+
+// 本身已经压缩过的格式，不再尝试压缩
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "mp4", "m4v", "mov", "mkv",
+    "webm", "avi", "mp3", "m4a", "aac", "ogg", "opus", "flac", "zip", "gz", "tgz", "bz2", "xz",
+    "zst", "lz4", "7z", "rar", "jar", "apk", "ipa", "docx", "xlsx", "pptx", "epub", "dmg",
+];
+
+// 协商的压缩算法，"none" 时为 None
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn from_session(session: &SessionParams) -> Option<Self> {
+        match session.compression.as_str() {
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Zstd => zstd::bulk::compress(bytes, ZSTD_LEVEL)
+                .map_err(|e| format!("Failed to compress data: {}", e)),
+            Codec::Lz4 => Ok(lz4_flex::block::compress(bytes)),
+        }
+    }
+
+    // raw_len 为发送方声明的原始长度，解压结果不会超过它
+    pub fn decompress(self, bytes: &[u8], raw_len: usize) -> Result<Vec<u8>, String> {
+        let raw = match self {
+            Codec::Zstd => zstd::bulk::decompress(bytes, raw_len)
+                .map_err(|e| format!("Failed to decompress data: {}", e))?,
+            Codec::Lz4 => lz4_flex::block::decompress(bytes, raw_len)
+                .map_err(|e| format!("Failed to decompress data: {}", e))?,
+        };
+        if raw.len() != raw_len {
+            return Err(format!(
+                "Decompressed {} bytes, expected {}",
+                raw.len(),
+                raw_len
+            ));
+        }
+        Ok(raw)
+    }
+}
+
+// 发送一个文件时决定每个数据块是否压缩: 已压缩的格式直接跳过，
+// 其余先压缩一小段样本，效果不好时暂停压缩一段时间再重新取样
+pub struct Compressor {
+    codec: Codec,
+    // 文件本身是压缩格式，整个文件都不再尝试
+    skipped: bool,
+    sniffed: bool,
+    probe: bool,
+    idle: u32,
+}
+
+impl Compressor {
+    pub fn new(codec: Codec, path: &Path) -> Self {
+        let skipped = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                INCOMPRESSIBLE_EXTENSIONS
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(ext))
+            });
+        Self {
+            skipped,
+            sniffed: false,
+            ..Self::for_stream(codec)
+        }
+    }
+
+    // 数据不是单个文件的内容，例如打包的小文件，只通过取样判断
+    pub fn for_stream(codec: Codec) -> Self {
+        Self {
+            codec,
+            skipped: false,
+            sniffed: true,
+            probe: true,
+            idle: 0,
+        }
+    }
+
+    // 按扩展名已经确定整个文件不压缩，发送前不需要读出内容
+    pub fn skips_file(&self) -> bool {
+        self.skipped
+    }
+
+    // 值得压缩时返回压缩后的数据，否则按原样发送
+    pub fn compress(&mut self, bytes: &[u8]) -> Option<Vec<u8>> {
+        if self.skipped {
+            return None;
+        }
+        // 扩展名不可靠，再根据文件开头的魔数识别
+        if !self.sniffed {
+            self.sniffed = true;
+            if is_compressed_format(bytes) {
+                self.skipped = true;
+                return None;
+            }
+        }
+        if self.idle > 0 {
+            self.idle -= 1;
+            return None;
+        }
+
+        if self.probe {
+            let sample = &bytes[..bytes.len().min(SAMPLE_LEN)];
+            if sample.len() < bytes.len() && self.shrink(sample).is_none() {
+                self.idle = IDLE_CHUNKS;
+                return None;
+            }
+            self.probe = false;
+        }
+
+        let compressed = self.shrink(bytes);
+        if compressed.is_none() {
+            self.probe = true;
+            self.idle = IDLE_CHUNKS;
+        }
+        compressed
+    }
+
+    fn shrink(&mut self, bytes: &[u8]) -> Option<Vec<u8>> {
+        match self.codec.compress(bytes) {
+            Ok(compressed) if compressed.len() + bytes.len() / MIN_SAVING < bytes.len() => {
+                Some(compressed)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("{}, sending uncompressed", e);
+                self.skipped = true;
+                None
+            }
+        }
+    }
+}
+
+// 常见图片、音视频和压缩包的文件头
+fn is_compressed_format(bytes: &[u8]) -> bool {
+    const SIGNATURES: &[&[u8]] = &[
+        b"\xFF\xD8\xFF",       // JPEG
+        b"\x89PNG",            // PNG
+        b"GIF8",               // GIF
+        b"PK\x03\x04",         // ZIP 及 Office 文档
+        b"\x1F\x8B",           // gzip
+        b"BZh",                // bzip2
+        b"\xFD7zXZ\x00",       // xz
+        b"\x28\xB5\x2F\xFD",   // zstd
+        b"\x04\x22\x4D\x18",   // lz4
+        b"7z\xBC\xAF\x27\x1C", // 7z
+        b"Rar!",               // RAR
+        b"\x1A\x45\xDF\xA3",   // Matroska / WebM
+        b"OggS",               // Ogg
+        b"fLaC",               // FLAC
+        b"ID3",                // MP3
+    ];
+    if SIGNATURES.iter().any(|sig| bytes.starts_with(sig)) {
+        return true;
+    }
+    // MP4、MOV、HEIC 等 ISO 媒体文件在第 4 字节处是 ftyp，WebP 是 RIFF 容器
+    bytes.get(4..8) == Some(b"ftyp".as_slice())
+        || (bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP".as_slice()))
+}
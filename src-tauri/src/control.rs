@@ -0,0 +1,132 @@
+use crate::protocol::ControlAction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+impl ControlState {
+    fn apply(self, action: ControlAction) -> Self {
+        match (self, action) {
+            // 取消之后不能再恢复
+            (ControlState::Cancelled, _) => ControlState::Cancelled,
+            (_, ControlAction::Pause) => ControlState::Paused,
+            (_, ControlAction::Resume) => ControlState::Running,
+            (_, ControlAction::Cancel) => ControlState::Cancelled,
+        }
+    }
+
+    pub fn action(self) -> ControlAction {
+        match self {
+            ControlState::Running => ControlAction::Resume,
+            ControlState::Paused => ControlAction::Pause,
+            ControlState::Cancelled => ControlAction::Cancel,
+        }
+    }
+}
+
+// 正在进行的传输，按 transfer_id 查找控制通道
+#[derive(Clone, Default)]
+pub struct TransferControls {
+    transfers: Arc<Mutex<HashMap<String, Arc<watch::Sender<ControlState>>>>>,
+}
+
+impl TransferControls {
+    pub async fn register(&self, transfer_id: &str) -> TransferControl {
+        let (state_tx, changes) = watch::channel(ControlState::Running);
+        let state = Arc::new(state_tx);
+        self.transfers
+            .lock()
+            .await
+            .insert(transfer_id.to_string(), state.clone());
+
+        TransferControl {
+            transfer_id: transfer_id.to_string(),
+            state,
+            changes,
+            announced: ControlState::Running,
+        }
+    }
+
+    pub async fn unregister(&self, transfer_id: &str) {
+        self.transfers.lock().await.remove(transfer_id);
+    }
+
+    pub async fn state(&self, transfer_id: &str) -> Option<ControlState> {
+        self.transfers
+            .lock()
+            .await
+            .get(transfer_id)
+            .map(|state| *state.borrow())
+    }
+
+    // 界面发出的指令，由传输任务在下一个数据块之前处理
+    pub async fn apply(&self, transfer_id: &str, action: ControlAction) -> Result<(), String> {
+        let transfers = self.transfers.lock().await;
+        let state = transfers
+            .get(transfer_id)
+            .ok_or_else(|| format!("Transfer not found: {}", transfer_id))?;
+        state.send_modify(|state| *state = state.apply(action));
+        Ok(())
+    }
+}
+
+// 传输任务持有的一端
+pub struct TransferControl {
+    pub transfer_id: String,
+    state: Arc<watch::Sender<ControlState>>,
+    changes: watch::Receiver<ControlState>,
+    // 已经通知过对端的状态，避免把对端发来的指令再发回去
+    announced: ControlState,
+}
+
+impl TransferControl {
+    pub fn state(&self) -> ControlState {
+        *self.state.borrow()
+    }
+
+    // 对端发来的指令，对端已经知道，不需要再通知
+    pub fn apply_remote(&mut self, action: ControlAction) -> ControlState {
+        self.state.send_modify(|state| *state = state.apply(action));
+        self.announced = self.state();
+        self.announced
+    }
+
+    // 本地指令改变的状态，需要通知对端
+    pub fn take_change(&mut self) -> Option<ControlState> {
+        let state = self.state();
+        if state == self.announced {
+            return None;
+        }
+        self.announced = state;
+        Some(state)
+    }
+
+    // 并行发送数据的任务各自订阅状态变化
+    pub fn subscribe(&self) -> watch::Receiver<ControlState> {
+        self.state.subscribe()
+    }
+
+    pub async fn changed(&mut self) {
+        // 发送端保存在 self 中，不会被关闭
+        let _ = self.changes.changed().await;
+    }
+
+    // 暂停时等待继续或取消，返回 false 表示已取消
+    pub async fn wait_until_running(&mut self) -> bool {
+        loop {
+            match self.state() {
+                ControlState::Running => return true,
+                ControlState::Cancelled => return false,
+                ControlState::Paused => self.changed().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::manifest;
+use crate::settings;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+const INDEX_FILE: &str = "content_index.json";
+// 只保留最近用到的文件，避免索引无限增长
+const MAX_ENTRIES: usize = 50_000;
+const COPY_BUFFER_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct IndexEntry {
+    path: PathBuf,
+    size: u64,
+    modified: Option<i64>,
+    sha256: String,
+    // 最近一次记录或命中的时间，毫秒级 Unix 时间戳
+    used: i64,
+    // 由传输接收并校验过的文件。只有这些文件可以代替传输，
+    // 否则发送方可以用清单中的校验和探测接收方有哪些文件
+    #[serde(default)]
+    received: bool,
+}
+
+impl IndexEntry {
+    // 文件大小或修改时间变化后记录的校验和不再可信
+    fn matches(&self, metadata: &Metadata) -> bool {
+        metadata.is_file()
+            && self.size == metadata.len()
+            && self.modified == manifest::modified_millis(metadata)
+    }
+}
+
+// 本机文件的内容索引: 发送时缓存文件的校验和，接收时查找内容相同的已接收文件
+pub struct ContentIndex {
+    entries: HashMap<PathBuf, IndexEntry>,
+    // 只包含已接收的文件
+    by_hash: HashMap<String, PathBuf>,
+    dirty: bool,
+}
+
+impl ContentIndex {
+    pub fn load() -> Self {
+        let entries: Vec<IndexEntry> = settings::app_data_dir()
+            .ok()
+            .and_then(|dir| std::fs::read_to_string(dir.join(INDEX_FILE)).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        let mut index = Self {
+            entries: HashMap::new(),
+            by_hash: HashMap::new(),
+            dirty: false,
+        };
+        for entry in entries {
+            index.insert(entry);
+        }
+        index
+    }
+
+    // 记录的文件仍然存在且未被修改时返回它的校验和
+    pub fn cached_hash(&self, path: &Path, metadata: &Metadata) -> Option<String> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.matches(metadata))
+            .map(|entry| entry.sha256.clone())
+    }
+
+    // 记录本机文件的校验和，之前接收的文件未被修改时仍视为已接收
+    pub fn record(&mut self, path: &Path, metadata: &Metadata, sha256: &str) {
+        let sha256 = sha256.to_lowercase();
+        let received = self.entries.get(path).is_some_and(|entry| {
+            entry.received && entry.sha256 == sha256 && entry.matches(metadata)
+        });
+        self.insert_recorded(path, metadata, sha256, received);
+    }
+
+    // 记录传输接收并校验过的文件
+    pub fn record_received(&mut self, path: &Path, metadata: &Metadata, sha256: &str) {
+        self.insert_recorded(path, metadata, sha256.to_lowercase(), true);
+    }
+
+    fn insert_recorded(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        sha256: String,
+        received: bool,
+    ) {
+        self.insert(IndexEntry {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified: manifest::modified_millis(metadata),
+            sha256,
+            used: chrono::Local::now().timestamp_millis(),
+            received,
+        });
+        self.dirty = true;
+    }
+
+    // 查找内容相同且仍未被修改的已接收文件
+    pub fn find(&mut self, sha256: &str, size: u64) -> Option<PathBuf> {
+        let path = self.by_hash.get(&sha256.to_lowercase())?.clone();
+        let entry = self.entries.get_mut(&path)?;
+        match std::fs::metadata(&path) {
+            Ok(metadata) if entry.size == size && entry.matches(&metadata) => {
+                entry.used = chrono::Local::now().timestamp_millis();
+                self.dirty = true;
+                Some(path)
+            }
+            _ => {
+                self.remove(&path);
+                None
+            }
+        }
+    }
+
+    // 每次传输结束后保存一次
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if self.entries.len() > MAX_ENTRIES {
+            let mut used: Vec<i64> = self.entries.values().map(|entry| entry.used).collect();
+            used.sort_unstable();
+            let cutoff = used[self.entries.len() - MAX_ENTRIES];
+            let stale: Vec<PathBuf> = self
+                .entries
+                .values()
+                .filter(|entry| entry.used < cutoff)
+                .map(|entry| entry.path.clone())
+                .collect();
+            for path in stale {
+                self.remove(&path);
+            }
+        }
+
+        let result = settings::app_data_dir().and_then(|dir| {
+            std::fs::create_dir_all(&dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+            let entries: Vec<&IndexEntry> = self.entries.values().collect();
+            let content = serde_json::to_string(&entries)
+                .map_err(|e| format!("Failed to serialize content index: {}", e))?;
+            std::fs::write(dir.join(INDEX_FILE), content)
+                .map_err(|e| format!("Failed to write content index: {}", e))
+        });
+        if let Err(e) = result {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn insert(&mut self, entry: IndexEntry) {
+        let path = entry.path.clone();
+        if entry.received {
+            self.by_hash.insert(entry.sha256.clone(), path.clone());
+        }
+        if let Some(old) = self.entries.insert(path.clone(), entry) {
+            // 同一路径的旧内容不再可用
+            let entry = &self.entries[&path];
+            if self.by_hash.get(&old.sha256) == Some(&path)
+                && (entry.sha256 != old.sha256 || !entry.received)
+            {
+                self.by_hash.remove(&old.sha256);
+            }
+        }
+    }
+
+    // 校验发现记录的内容与文件不符时移除
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            if self.by_hash.get(&entry.sha256) == Some(&entry.path) {
+                self.by_hash.remove(&entry.sha256);
+            }
+            self.dirty = true;
+        }
+    }
+}
+
+// 发送方计算文件的 SHA-256，文件未修改时直接使用索引中的结果
+pub async fn cached_hash(index: &Mutex<ContentIndex>, path: &Path) -> Result<String, String> {
+    let metadata = fs::metadata(path)
+        .await
+        .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+    if let Some(sha256) = index.lock().await.cached_hash(path, &metadata) {
+        return Ok(sha256);
+    }
+
+    let sha256 = hash_file(path).await?;
+    // 读取期间文件被修改时不记录
+    let after = fs::metadata(path)
+        .await
+        .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+    if after.len() == metadata.len() && after.modified().ok() == metadata.modified().ok() {
+        index.lock().await.record(path, &metadata, &sha256);
+    }
+    Ok(sha256)
+}
+
+pub async fn hash_file(path: &Path) -> Result<String, String> {
+    let mut file = fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to open file: {}", e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; COPY_BUFFER_LEN];
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// 把本机已有的文件复制到 dest，同时校验内容，不一致时删除 dest 并返回 false。
+// 索引只是线索，文件可能在两次传输之间被改过而修改时间没变
+pub async fn copy_verified(source: &Path, dest: &Path, sha256: &str) -> Result<bool, String> {
+    let result = async {
+        let mut reader = fs::File::open(source)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", source.display(), e))?;
+        let mut writer = fs::File::create(dest)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", dest.display(), e))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; COPY_BUFFER_LEN];
+        loop {
+            let bytes_read = reader
+                .read(&mut buffer)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", source.display(), e))?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+            writer
+                .write_all(&buffer[..bytes_read])
+                .await
+                .map_err(|e| format!("Failed to write to file: {}", e))?;
+        }
+        writer
+            .sync_all()
+            .await
+            .map_err(|e| format!("Failed to sync file: {}", e))?;
+        Ok::<_, String>(format!("{:x}", hasher.finalize()).eq_ignore_ascii_case(sha256))
+    }
+    .await;
+
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(dest).await;
+    }
+    result
+}
@@ -0,0 +1,629 @@
+use crate::protocol::{Frame, FrameType, MAX_FRAME_LEN};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// 不小于该大小且接收方已有旧版本的文件只发送与旧版本不同的部分
+pub const DELTA_MIN_SIZE: u64 = 4 * 1024 * 1024;
+// 块大小约为旧版本大小的平方根，块数不超过 MAX_BLOCKS 以免签名超出单帧上限
+const MIN_BLOCK_SIZE: u32 = 2 * 1024;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+const MAX_BLOCKS: u64 = 512 * 1024;
+// 每块的强校验和取 SHA-256 的前 16 字节
+const STRONG_LEN: usize = 16;
+// Signature 帧负载头: 4 字节文件序号 + 4 字节块大小 + 8 字节旧版本大小
+const SIGNATURE_HEADER_LEN: usize = 16;
+const BLOCK_SIGNATURE_LEN: usize = 4 + STRONG_LEN;
+// 一条引用指令最多覆盖的字节数，接收方按它读取旧版本
+const MAX_COPY_LEN: u64 = 8 * 1024 * 1024;
+// 一个 Delta 帧最多还原的字节数，保证进度和暂停及时响应
+const MAX_FRAME_BYTES: u64 = 32 * 1024 * 1024;
+// 发送方每次从文件读取的大小
+const READ_LEN: usize = 1024 * 1024;
+// Delta 帧中的指令: 新数据为 1 字节类型 + 4 字节长度 + 数据，
+// 引用旧版本为 1 字节类型 + 4 字节起始块号 + 4 字节块数
+const OP_LITERAL: u8 = 0;
+const OP_COPY: u8 = 1;
+
+// rsync 的滚动校验和，窗口每次后移一个字节时可以直接更新
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(bytes: &[u8]) -> Self {
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in bytes.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((bytes.len() - i) as u32 * byte as u32);
+        }
+        Self {
+            a,
+            b,
+            len: bytes.len() as u32,
+        }
+    }
+
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(bytes: &[u8]) -> [u8; STRONG_LEN] {
+    let mut strong = [0u8; STRONG_LEN];
+    strong.copy_from_slice(&Sha256::digest(bytes)[..STRONG_LEN]);
+    strong
+}
+
+fn block_size(len: u64) -> u32 {
+    let root = ((len as f64).sqrt() as u64).next_power_of_two();
+    root.max(len.div_ceil(MAX_BLOCKS))
+        .clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+// 接收方旧版本的块签名，最后一块可能不满
+pub struct Signature {
+    pub file_index: u32,
+    block_size: u32,
+    len: u64,
+    blocks: Vec<(u32, [u8; STRONG_LEN])>,
+}
+
+impl Signature {
+    // 旧版本无法读取时发送空签名，发送方会发送完整内容
+    pub fn empty(file_index: u32) -> Self {
+        Self {
+            file_index,
+            block_size: MIN_BLOCK_SIZE,
+            len: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn frame(&self) -> Frame {
+        let mut payload =
+            Vec::with_capacity(SIGNATURE_HEADER_LEN + self.blocks.len() * BLOCK_SIGNATURE_LEN);
+        payload.extend_from_slice(&self.file_index.to_be_bytes());
+        payload.extend_from_slice(&self.block_size.to_be_bytes());
+        payload.extend_from_slice(&self.len.to_be_bytes());
+        for (weak, strong) in &self.blocks {
+            payload.extend_from_slice(&weak.to_be_bytes());
+            payload.extend_from_slice(strong);
+        }
+        Frame::new(FrameType::Signature, payload)
+    }
+
+    pub fn parse(frame: &Frame) -> Result<Self, String> {
+        let payload = &frame.payload;
+        if payload.len() < SIGNATURE_HEADER_LEN
+            || !(payload.len() - SIGNATURE_HEADER_LEN).is_multiple_of(BLOCK_SIGNATURE_LEN)
+        {
+            return Err("Malformed signature frame".to_string());
+        }
+
+        let mut signature = Self {
+            file_index: read_u32(&payload[..4]),
+            block_size: read_u32(&payload[4..8]),
+            len: read_u64(&payload[8..16]),
+            blocks: Vec::new(),
+        };
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&signature.block_size) {
+            return Err(format!("Invalid block size {}", signature.block_size));
+        }
+        for block in payload[SIGNATURE_HEADER_LEN..].chunks_exact(BLOCK_SIGNATURE_LEN) {
+            let mut strong = [0u8; STRONG_LEN];
+            strong.copy_from_slice(&block[4..]);
+            signature.blocks.push((read_u32(&block[..4]), strong));
+        }
+        if signature.blocks.len() as u64 != signature.len.div_ceil(signature.block_size as u64) {
+            return Err("Signature does not match the announced size".to_string());
+        }
+        Ok(signature)
+    }
+
+    // 最后一块的长度，旧版本大小是块大小的整数倍时为 0
+    fn tail_len(&self) -> usize {
+        (self.len % self.block_size as u64) as usize
+    }
+}
+
+// 接收方的旧版本，还原新文件时按块号读取
+pub struct Basis {
+    file: fs::File,
+    block_size: u32,
+    len: u64,
+}
+
+impl Basis {
+    // 打开旧版本并计算块签名
+    pub async fn open(file_index: u32, path: &Path) -> Result<(Signature, Self), String> {
+        let mut file = fs::File::open(path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to get file metadata: {}", e))?
+            .len();
+
+        let block_size = block_size(len);
+        let mut signature = Signature {
+            file_index,
+            block_size,
+            len,
+            blocks: Vec::new(),
+        };
+        let mut buffer = vec![0; block_size as usize];
+        let mut remaining = len;
+        while remaining > 0 {
+            let block = &mut buffer[..remaining.min(block_size as u64) as usize];
+            file.read_exact(block)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            signature
+                .blocks
+                .push((Rolling::new(block).digest(), strong_hash(block)));
+            remaining -= block.len() as u64;
+        }
+
+        Ok((
+            signature,
+            Self {
+                file,
+                block_size,
+                len,
+            },
+        ))
+    }
+
+    // 读取从 block 开始的 count 个块
+    pub async fn read(&mut self, block: u32, count: u32) -> Result<Vec<u8>, String> {
+        let start = block as u64 * self.block_size as u64;
+        let end = (start + count as u64 * self.block_size as u64).min(self.len);
+        if count == 0 || start >= end || end - start > MAX_FRAME_LEN as u64 {
+            return Err(format!("Invalid block reference {}+{}", block, count));
+        }
+
+        self.file
+            .seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| format!("Failed to seek file: {}", e))?;
+        let mut bytes = vec![0; (end - start) as usize];
+        self.file
+            .read_exact(&mut bytes)
+            .await
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        Ok(bytes)
+    }
+}
+
+pub enum DeltaOp<'a> {
+    Literal(&'a [u8]),
+    Copy { block: u32, count: u32 },
+}
+
+// 解开一个 Delta 帧，返回文件序号和其中的指令
+pub fn parse(frame: &Frame) -> Result<(u32, Vec<DeltaOp<'_>>), String> {
+    let payload = &frame.payload;
+    if payload.len() < 4 {
+        return Err("Delta frame too short".to_string());
+    }
+    let file_index = read_u32(&payload[..4]);
+
+    let mut ops = Vec::new();
+    let mut rest = &payload[4..];
+    while let Some((&op, tail)) = rest.split_first() {
+        match op {
+            OP_LITERAL if tail.len() >= 4 => {
+                let len = read_u32(&tail[..4]) as usize;
+                if tail.len() - 4 < len {
+                    return Err("Truncated delta literal".to_string());
+                }
+                ops.push(DeltaOp::Literal(&tail[4..4 + len]));
+                rest = &tail[4 + len..];
+            }
+            OP_COPY if tail.len() >= 8 => {
+                ops.push(DeltaOp::Copy {
+                    block: read_u32(&tail[..4]),
+                    count: read_u32(&tail[4..8]),
+                });
+                rest = &tail[8..];
+            }
+            _ => return Err(format!("Malformed delta instruction {}", op)),
+        }
+    }
+    Ok((file_index, ops))
+}
+
+// 发送的一个 Delta 帧
+pub struct DeltaChunk {
+    pub frame: Frame,
+    // 这些指令还原出的字节数，以及其中引用旧版本的部分
+    pub bytes: u64,
+    pub reused: u64,
+}
+
+// 发送方按接收方的块签名扫描新文件: 窗口与旧版本的某一块相同时发送块号，
+// 否则窗口后移一个字节，移出的字节作为新数据发送
+pub struct DeltaEncoder {
+    file: fs::File,
+    signature: Signature,
+    // 弱校验和到块号的索引，最后一块不满时单独匹配
+    table: HashMap<u32, Vec<u32>>,
+    hasher: Sha256,
+    size: u64,
+    eof: bool,
+    // 已读入但尚未处理完的数据，窗口从 pos 开始，literal 到 pos 之间是待发送的新数据
+    buf: Vec<u8>,
+    pos: usize,
+    literal: usize,
+    rolling: Option<Rolling>,
+    // 尚未写入帧的连续引用
+    run: Option<(u32, u32)>,
+    // 引用旧版本的字节数
+    reused: u64,
+}
+
+impl DeltaEncoder {
+    pub async fn new(path: &Path, signature: Signature) -> Result<Self, String> {
+        let file = fs::File::open(path)
+            .await
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+
+        let full_blocks = signature.len / signature.block_size as u64;
+        let mut table: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (block, (weak, _)) in signature.blocks.iter().enumerate() {
+            if (block as u64) < full_blocks {
+                table.entry(*weak).or_default().push(block as u32);
+            }
+        }
+
+        Ok(Self {
+            file,
+            signature,
+            table,
+            hasher: Sha256::new(),
+            size: 0,
+            eof: false,
+            buf: Vec::new(),
+            pos: 0,
+            literal: 0,
+            rolling: None,
+            run: None,
+            reused: 0,
+        })
+    }
+
+    // 生成下一个 Delta 帧，负载约为 max_len 字节，文件扫描完时返回 None
+    pub async fn next_chunk(&mut self, max_len: usize) -> Result<Option<DeltaChunk>, String> {
+        let block_size = self.signature.block_size as usize;
+        let mut payload = self.signature.file_index.to_be_bytes().to_vec();
+        let mut bytes = 0u64;
+        let reused = self.reused;
+
+        while payload.len() < max_len && bytes < MAX_FRAME_BYTES {
+            // 新数据太多时先发出去，缓冲区不会无限增长
+            if self.pos - self.literal >= max_len {
+                self.flush_literal(&mut payload, &mut bytes);
+                continue;
+            }
+            if self.buf.len() - self.pos < block_size + 1 && !self.eof {
+                self.fill().await?;
+                continue;
+            }
+            // 旧版本不足一块时没有可以引用的块，全部作为新数据
+            if self.table.is_empty() && self.signature.tail_len() == 0 {
+                self.pos = self.buf.len();
+            }
+
+            let available = self.buf.len() - self.pos;
+            if available < block_size {
+                // 文件末尾不足一块，可能与旧版本的最后一块相同
+                let tail = self.signature.tail_len();
+                if available > 0 && available == tail {
+                    let window = &self.buf[self.pos..];
+                    let last = self.signature.blocks.len() - 1;
+                    if self.signature.blocks[last]
+                        == (Rolling::new(window).digest(), strong_hash(window))
+                    {
+                        self.flush_literal(&mut payload, &mut bytes);
+                        self.push_copy(last as u32, tail as u64, &mut payload, &mut bytes);
+                        self.pos += tail;
+                        self.literal = self.pos;
+                    }
+                }
+                self.pos = self.buf.len();
+                self.flush_literal(&mut payload, &mut bytes);
+                self.flush_run(&mut payload);
+                break;
+            }
+
+            let window = &self.buf[self.pos..self.pos + block_size];
+            let rolling = *self.rolling.get_or_insert_with(|| Rolling::new(window));
+            if let Some(block) = self.find_block(rolling.digest(), window) {
+                self.flush_literal(&mut payload, &mut bytes);
+                self.push_copy(block, block_size as u64, &mut payload, &mut bytes);
+                self.pos += block_size;
+                self.literal = self.pos;
+                self.rolling = None;
+            } else {
+                let mut rolling = rolling;
+                if self.pos + block_size < self.buf.len() {
+                    rolling.roll(self.buf[self.pos], self.buf[self.pos + block_size]);
+                    self.rolling = Some(rolling);
+                } else {
+                    self.rolling = None;
+                }
+                self.pos += 1;
+            }
+        }
+        self.flush_run(&mut payload);
+
+        if payload.len() == 4 {
+            return Ok(None);
+        }
+        Ok(Some(DeltaChunk {
+            frame: Frame::new(FrameType::Delta, payload),
+            bytes,
+            reused: self.reused - reused,
+        }))
+    }
+
+    // 返回新文件的大小、SHA-256 和引用旧版本的字节数
+    pub fn finish(self) -> (u64, String, u64) {
+        (
+            self.size,
+            format!("{:x}", self.hasher.finalize()),
+            self.reused,
+        )
+    }
+
+    // 读入更多数据，先丢掉已经处理完的部分
+    async fn fill(&mut self) -> Result<(), String> {
+        if self.literal > 0 {
+            self.buf.drain(..self.literal);
+            self.pos -= self.literal;
+            self.literal = 0;
+        }
+
+        let start = self.buf.len();
+        self.buf.resize(start + READ_LEN, 0);
+        let bytes_read = self
+            .file
+            .read(&mut self.buf[start..])
+            .await
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        self.buf.truncate(start + bytes_read);
+        if bytes_read == 0 {
+            self.eof = true;
+        }
+        self.hasher.update(&self.buf[start..]);
+        self.size += bytes_read as u64;
+        Ok(())
+    }
+
+    // 弱校验和相同时再比较强校验和，优先选择紧接上一次引用的块
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.table.get(&weak)?;
+        let strong = strong_hash(window);
+        let next = self.run.map(|(start, count)| start + count);
+        let mut found = None;
+        for &block in candidates {
+            if self.signature.blocks[block as usize].1 == strong {
+                if Some(block) == next {
+                    return Some(block);
+                }
+                found.get_or_insert(block);
+            }
+        }
+        found
+    }
+
+    fn flush_literal(&mut self, payload: &mut Vec<u8>, bytes: &mut u64) {
+        if self.pos == self.literal {
+            return;
+        }
+        self.flush_run(payload);
+        let literal = &self.buf[self.literal..self.pos];
+        payload.push(OP_LITERAL);
+        payload.extend_from_slice(&(literal.len() as u32).to_be_bytes());
+        payload.extend_from_slice(literal);
+        *bytes += literal.len() as u64;
+        self.literal = self.pos;
+    }
+
+    fn push_copy(&mut self, block: u32, len: u64, payload: &mut Vec<u8>, bytes: &mut u64) {
+        let block_size = self.signature.block_size as u64;
+        self.run = match self.run {
+            Some((start, count))
+                if start + count == block && (count as u64 + 1) * block_size <= MAX_COPY_LEN =>
+            {
+                Some((start, count + 1))
+            }
+            _ => {
+                self.flush_run(payload);
+                Some((block, 1))
+            }
+        };
+        self.reused += len;
+        *bytes += len;
+    }
+
+    fn flush_run(&mut self, payload: &mut Vec<u8>) {
+        if let Some((block, count)) = self.run.take() {
+            payload.push(OP_COPY);
+            payload.extend_from_slice(&block.to_be_bytes());
+            payload.extend_from_slice(&count.to_be_bytes());
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(bytes);
+    u32::from_be_bytes(value)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes);
+    u64::from_be_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // 固定种子的伪随机数据，块之间不会偶然相同
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lantransfer-delta-{}-{}", std::process::id(), name))
+    }
+
+    // 按接收方的流程还原新文件，返回还原结果和引用旧版本的字节数
+    async fn round_trip(name: &str, old: &[u8], new: &[u8]) -> (Vec<u8>, u64) {
+        let basis_path = temp_path(&format!("{}-basis", name));
+        let source_path = temp_path(&format!("{}-source", name));
+        std::fs::write(&basis_path, old).unwrap();
+        std::fs::write(&source_path, new).unwrap();
+
+        let (signature, mut basis) = Basis::open(7, &basis_path).await.unwrap();
+        let signature = Signature::parse(&signature.frame()).unwrap();
+        let mut encoder = DeltaEncoder::new(&source_path, signature).await.unwrap();
+
+        let mut output = Vec::new();
+        let mut reused = 0;
+        while let Some(chunk) = encoder.next_chunk(64 * 1024).await.unwrap() {
+            let (file_index, ops) = parse(&chunk.frame).unwrap();
+            assert_eq!(file_index, 7);
+            let start = output.len();
+            for op in ops {
+                match op {
+                    DeltaOp::Literal(bytes) => output.extend_from_slice(bytes),
+                    DeltaOp::Copy { block, count } => {
+                        output.extend(basis.read(block, count).await.unwrap())
+                    }
+                }
+            }
+            assert_eq!((output.len() - start) as u64, chunk.bytes);
+            reused += chunk.reused;
+        }
+
+        let (size, sha256, total_reused) = encoder.finish();
+        assert_eq!(size, new.len() as u64);
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(new)));
+        assert_eq!(total_reused, reused);
+
+        let _ = std::fs::remove_file(&basis_path);
+        let _ = std::fs::remove_file(&source_path);
+        (output, reused)
+    }
+
+    #[tokio::test]
+    async fn empty_basis_sends_everything() {
+        let new = random_bytes(100_000, 1);
+        let (output, reused) = round_trip("empty", &[], &new).await;
+        assert_eq!(output, new);
+        assert_eq!(reused, 0);
+
+        let (output, reused) = round_trip("both-empty", &[], &[]).await;
+        assert!(output.is_empty());
+        assert_eq!(reused, 0);
+    }
+
+    #[tokio::test]
+    async fn basis_shorter_than_one_block() {
+        let old = random_bytes(1000, 2);
+        assert!(old.len() < MIN_BLOCK_SIZE as usize);
+
+        let (output, reused) = round_trip("short-same", &old, &old).await;
+        assert_eq!(output, old);
+        assert_eq!(reused, old.len() as u64);
+
+        let mut new = old.clone();
+        new.extend(random_bytes(50_000, 3));
+        let (output, _) = round_trip("short-grown", &old, &new).await;
+        assert_eq!(output, new);
+    }
+
+    #[tokio::test]
+    async fn tail_block_is_reused() {
+        let block = MIN_BLOCK_SIZE as usize;
+        let old = random_bytes(block * 100 + 123, 4);
+        assert_eq!(block_size(old.len() as u64) as usize, block);
+
+        let (output, reused) = round_trip("tail-same", &old, &old).await;
+        assert_eq!(output, old);
+        assert_eq!(reused, old.len() as u64);
+
+        // 只改动第一块，其余包括不满的最后一块都引用旧版本
+        let mut new = old.clone();
+        new[10] ^= 0xff;
+        let (output, reused) = round_trip("tail-changed-head", &old, &new).await;
+        assert_eq!(output, new);
+        assert_eq!(reused, (old.len() - block) as u64);
+
+        // 最后一块改动后作为新数据发送
+        let mut new = old.clone();
+        *new.last_mut().unwrap() ^= 0xff;
+        let (output, reused) = round_trip("tail-changed", &old, &new).await;
+        assert_eq!(output, new);
+        assert_eq!(reused, (old.len() - 123) as u64);
+    }
+
+    #[tokio::test]
+    async fn inserted_and_deleted_bytes_mid_file() {
+        let old = random_bytes(1024 * 1024 + 777, 5);
+        let block = block_size(old.len() as u64) as u64;
+
+        let mut inserted = old[..500_000].to_vec();
+        inserted.extend_from_slice(b"inserted in the middle");
+        inserted.extend_from_slice(&old[500_000..]);
+        let (output, reused) = round_trip("inserted", &old, &inserted).await;
+        assert_eq!(output, inserted);
+        assert!(reused >= old.len() as u64 - 2 * block, "reused {}", reused);
+
+        let mut deleted = old[..300_000].to_vec();
+        deleted.extend_from_slice(&old[300_100..]);
+        let (output, reused) = round_trip("deleted", &old, &deleted).await;
+        assert_eq!(output, deleted);
+        assert!(
+            reused >= deleted.len() as u64 - 2 * block,
+            "reused {}",
+            reused
+        );
+
+        // 连续的新数据超过一帧的大小时分成多个帧发送
+        let mut replaced = old.clone();
+        replaced[200_000..400_000].copy_from_slice(&random_bytes(200_000, 6));
+        let (output, _) = round_trip("replaced", &old, &replaced).await;
+        assert_eq!(output, replaced);
+    }
+}
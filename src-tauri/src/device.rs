@@ -1,7 +1,12 @@
+use crate::settings;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Notify;
 use uuid::Uuid;
 
+const DEVICE_ID_FILE: &str = "device_id";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Device {
     pub id: String,
@@ -14,6 +19,8 @@ pub struct Device {
 pub struct DeviceManager {
     current_device: Device,
     discovered_devices: HashMap<String, Device>,
+    // 有设备上线或离线时通知发送队列
+    changes: Arc<Notify>,
 }
 
 impl DeviceManager {
@@ -23,11 +30,12 @@ impl DeviceManager {
         Self {
             current_device,
             discovered_devices: HashMap::new(),
+            changes: Arc::new(Notify::new()),
         }
     }
 
     async fn create_current_device() -> Device {
-        let device_id = Uuid::new_v4().to_string();
+        let device_id = Self::load_device_id();
         let device_name = hostname::get()
             .unwrap_or_else(|_| "Unknown Device".into())
             .to_string_lossy()
@@ -48,6 +56,32 @@ impl DeviceManager {
         }
     }
 
+    // 设备 id 保存在数据目录中，重启后保持不变，对端据此识别同一台设备
+    fn load_device_id() -> String {
+        let path = match settings::app_data_dir() {
+            Ok(dir) => dir.join(DEVICE_ID_FILE),
+            Err(e) => {
+                log::warn!("{}", e);
+                return Uuid::new_v4().to_string();
+            }
+        };
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            if let Ok(id) = Uuid::parse_str(content.trim()) {
+                return id.to_string();
+            }
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, &id));
+        if let Err(e) = saved {
+            log::warn!("Failed to save device id to {}: {}", path.display(), e);
+        }
+        id
+    }
+
     fn detect_device_type() -> String {
         #[cfg(target_os = "windows")]
         return "desktop".to_string();
@@ -80,16 +114,23 @@ impl DeviceManager {
 
     pub fn add_device(&mut self, device: Device) {
         self.discovered_devices.insert(device.id.clone(), device);
+        self.changes.notify_one();
     }
 
     pub fn remove_device(&mut self, device_id: &str) {
         self.discovered_devices.remove(device_id);
     }
 
-    pub fn update_device_status(&mut self, device_id: &str, is_online: bool) {
-        if let Some(device) = self.discovered_devices.get_mut(device_id) {
-            device.is_online = is_online;
-        }
+    // 返回更新后的设备，未发现过该设备时返回 None
+    pub fn update_device_status(&mut self, device_id: &str, is_online: bool) -> Option<Device> {
+        let device = self.discovered_devices.get_mut(device_id)?;
+        device.is_online = is_online;
+        self.changes.notify_one();
+        Some(device.clone())
+    }
+
+    pub fn changes(&self) -> Arc<Notify> {
+        self.changes.clone()
     }
 
     pub fn get_devices(&self) -> Vec<Device> {
@@ -98,6 +139,11 @@ impl DeviceManager {
         devices
     }
 
+    // 只查找通过发现记录的其他设备，不包括本机
+    pub fn get_discovered_device(&self, device_id: &str) -> Option<&Device> {
+        self.discovered_devices.get(device_id)
+    }
+
     pub fn get_device_by_id(&self, device_id: &str) -> Option<&Device> {
         if self.current_device.id == device_id {
             Some(&self.current_device)
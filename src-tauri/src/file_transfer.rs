@@ -1,22 +1,219 @@
-use crate::device::Device;
+use crate::attributes;
+use crate::batch::{self, BatchFile, BatchWriter, BATCH_FILE_SIZE};
+use crate::collision::{self, CollisionPolicy};
+use crate::compression::{Codec, Compressor};
+use crate::control::{ControlState, TransferControl, TransferControls};
+use crate::dedup::{self, ContentIndex};
+use crate::delta::{self, Basis, DeltaEncoder, DeltaOp, Signature, DELTA_MIN_SIZE};
+use crate::device::{Device, DeviceManager};
+use crate::history::{TransferHistory, TransferRecord};
+use crate::links;
+use crate::manifest::{self, EntryKind, FileEntry, Manifest};
+use crate::partfile;
+use crate::progress::{ProgressSummary, ProgressTracker};
+use crate::protocol::{
+    self, AckMessage, ByteRange, Connection, ControlMessage, FileEndMessage, Frame, FrameType,
+    HelloMessage, JoinMessage, SessionParams, StripeMessage, ERROR_CHECKSUM_MISMATCH,
+    ERROR_INCOMPATIBLE, ERROR_INTERNAL, FEATURE_BATCH, FEATURE_CONTROL, FEATURE_DELTA,
+    FEATURE_LINKS, FEATURE_RESUME, FEATURE_SPARSE, MAX_CHECKSUM_RETRIES,
+};
+use crate::ratelimit::RateLimiter;
+use crate::resume::{self, PartialFile, ResumeState};
+use crate::sanitize;
+use crate::sendfile;
+use crate::settings::TransferSettings;
+use crate::sparse;
+use crate::stripe::{self, RangeProgress, StripeRegistry, StripeResult, Stripes, STRIPE_MIN_SIZE};
+use crate::tuning::{DiagnosticsRegistry, LinkDiagnostics, LinkTuning, SocketOptions};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use tauri::{Manager, Emitter};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::{Emitter, Manager};
+use tauri_plugin_notification::NotificationExt;
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinSet;
+use tokio::time;
+
+// 连接中断后发送方自动重连续传的次数和间隔
+const MAX_RESUME_ATTEMPTS: u32 = 3;
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(3);
+// 接收方每写入这么多数据记录一次断点
+const CHECKPOINT_INTERVAL: u64 = 32 * 1024 * 1024;
+const TRANSFER_CANCELLED: &str = "Transfer cancelled";
+// 并行数据连接出错后等待对端在控制连接上说明原因的时间
+const STRIPE_FAILURE_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferError {
+    pub peer: String,
+    pub message: String,
+}
+
+// 收到传输请求时通知界面，等待用户调用 respond_to_transfer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IncomingTransferRequest {
+    pub transfer_id: String,
+    pub sender: Device,
+    // 发送方是已发现的设备且连接地址与之一致
+    pub verified: bool,
+    pub files: Vec<FileEntry>,
+    pub file_count: usize,
+    pub total_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IncomingTransferExpired {
+    pub transfer_id: String,
+}
+
+// 传输开始时通知界面，之后可以用 transfer_id 暂停、继续或取消
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferStarted {
+    pub transfer_id: String,
+    // "send" 或 "receive"
+    pub direction: String,
+    pub peer: Device,
+    pub file_count: usize,
+    pub total_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferStateChanged {
+    pub transfer_id: String,
+    pub state: ControlState,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct TransferProgress {
-    pub file_name: String,
-    pub progress: f64,
+pub struct TransferFinished {
+    pub transfer_id: String,
     pub status: String,
+    #[serde(flatten)]
+    pub summary: ProgressSummary,
+}
+
+#[derive(Debug)]
+struct TransferDecision {
+    accept: bool,
+    save_dir: Option<PathBuf>,
+    // 为空时使用全局设置
+    collision_policy: Option<CollisionPolicy>,
+}
+
+// 等待用户答复的传输请求，按 transfer_id 查找。
+// 只在登记和取出时短暂加锁，答复命令不经过传输管理器
+#[derive(Clone, Default)]
+pub struct PendingDecisions {
+    requests: Arc<Mutex<HashMap<String, oneshot::Sender<TransferDecision>>>>,
+}
+
+impl PendingDecisions {
+    async fn register(&self, transfer_id: &str) -> oneshot::Receiver<TransferDecision> {
+        let (decision_tx, decision_rx) = oneshot::channel();
+        self.requests
+            .lock()
+            .await
+            .insert(transfer_id.to_string(), decision_tx);
+        decision_rx
+    }
+
+    async fn unregister(&self, transfer_id: &str) {
+        self.requests.lock().await.remove(transfer_id);
+    }
+
+    // 用户对 incoming-transfer-request 的答复
+    pub async fn respond(
+        &self,
+        transfer_id: &str,
+        accept: bool,
+        save_dir: Option<String>,
+        collision_policy: Option<CollisionPolicy>,
+    ) -> Result<(), String> {
+        let save_dir = match save_dir {
+            Some(dir) => {
+                let dir = PathBuf::from(dir);
+                if !dir.is_dir() {
+                    return Err(format!("Not a directory: {}", dir.display()));
+                }
+                Some(dir)
+            }
+            None => None,
+        };
+
+        let sender = self
+            .requests
+            .lock()
+            .await
+            .remove(transfer_id)
+            .ok_or("Transfer request not found or already expired")?;
+
+        sender
+            .send(TransferDecision {
+                accept,
+                save_dir,
+                collision_policy,
+            })
+            .map_err(|_| "Transfer request already expired".to_string())
+    }
+}
+
+// 接收端各连接共享的状态
+#[derive(Clone)]
+struct ReceiveContext {
+    app_handle: tauri::AppHandle,
+    settings: Arc<Mutex<TransferSettings>>,
+    device_manager: Arc<Mutex<DeviceManager>>,
+    history: Arc<Mutex<TransferHistory>>,
+    decisions: PendingDecisions,
+    controls: TransferControls,
+    stripes: StripeRegistry,
+    diagnostics: DiagnosticsRegistry,
+    content_index: Arc<Mutex<ContentIndex>>,
+}
+
+enum TransferOutcome {
+    Completed,
+    Declined(String),
+    // 重传后仍然校验失败的文件
+    ChecksumMismatch(Vec<String>),
+    // 文件都已收到，但这些链接被拒绝或无法创建
+    LinksFailed(Vec<String>),
+    // 对方接受后连接中断，可以用同一个 transfer_id 重连续传
+    Interrupted(String),
+    // 本地或对端取消了传输
+    Cancelled,
 }
 
+// 发送任务失败的原因，调度器据此决定是否重新排队
+#[derive(Debug)]
+pub enum SendError {
+    // 连接失败或传输中断，对端恢复后可以用同一个 transfer_id 续传
+    Interrupted(String),
+    // 被拒绝、校验失败或本地文件无法发送，重试也不会成功
+    Failed(String),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Interrupted(e) | SendError::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+// 一次会话的文件清单，整批文件只需要一次确认
 #[derive(Debug, Serialize, Deserialize)]
 struct FileTransferRequest {
-    file_name: String,
-    file_size: u64,
+    transfer_id: String,
+    files: Vec<FileEntry>,
+    total_size: u64,
     sender_device: Device,
 }
 
@@ -24,165 +221,979 @@ struct FileTransferRequest {
 struct FileTransferResponse {
     accepted: bool,
     message: String,
+    // 接收方不需要的文件序号，发送方直接跳过
+    #[serde(default)]
+    skip: Vec<u32>,
+    // 接收方已有部分数据的文件，发送方校验前缀一致后从断点继续
+    #[serde(default)]
+    resume: Vec<ResumeOffset>,
+    // skip 中接收方已有相同内容的文件
+    #[serde(default)]
+    identical: Vec<u32>,
+    // 接收方已有旧版本的文件，发送方等待接收方的块签名后只发送变化的部分
+    #[serde(default)]
+    delta: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ResumeOffset {
+    file_index: u32,
+    offset: u64,
+    // 前 offset 字节的 SHA-256
+    sha256: String,
+}
+
+// 发送方按接收方的答复安排每个文件，断点和差异只在第一次发送时使用
+struct SendPlan {
+    // 不需要发送的文件及其状态
+    skip: HashMap<u32, &'static str>,
+    resume: HashMap<u32, ResumeOffset>,
+    delta: HashSet<u32>,
+}
+
+impl SendPlan {
+    fn new(response: FileTransferResponse, session: &SessionParams) -> Self {
+        let supports = |feature: &str| session.features.iter().any(|f| f == feature);
+        let identical: HashSet<u32> = response.identical.into_iter().collect();
+        let skip = response
+            .skip
+            .into_iter()
+            .map(|index| {
+                let status = if identical.contains(&index) {
+                    "identical"
+                } else {
+                    "skipped"
+                };
+                (index, status)
+            })
+            .collect();
+        let resume = if supports(FEATURE_RESUME) {
+            response
+                .resume
+                .into_iter()
+                .map(|offset| (offset.file_index, offset))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let delta = if supports(FEATURE_DELTA) {
+            response.delta.into_iter().collect()
+        } else {
+            HashSet::new()
+        };
+        Self {
+            skip,
+            resume,
+            delta,
+        }
+    }
+}
+
+// 接收方临时文件的起点，没有时从头接收完整内容
+enum PartStart {
+    // 从断点继续，已有前缀的长度和校验状态
+    Resume(u64, Sha256),
+    // 按旧版本还原，发送方只发送变化的部分
+    Delta(PathBuf),
 }
 
 pub struct FileTransferManager {
     transfer_port: u16,
+    settings: Arc<Mutex<TransferSettings>>,
+    device_manager: Arc<Mutex<DeviceManager>>,
+    history: Arc<Mutex<TransferHistory>>,
+    decisions: PendingDecisions,
+    controls: TransferControls,
+    stripes: StripeRegistry,
+    diagnostics: DiagnosticsRegistry,
+    limiter: RateLimiter,
+    content_index: Arc<Mutex<ContentIndex>>,
 }
 
 impl FileTransferManager {
-    pub fn new() -> Self {
+    pub async fn new(
+        settings: Arc<Mutex<TransferSettings>>,
+        device_manager: Arc<Mutex<DeviceManager>>,
+    ) -> Self {
+        let limiter = RateLimiter::default();
+        limiter.configure(&*settings.lock().await);
         Self {
             transfer_port: 8081,
+            settings,
+            device_manager,
+            history: Arc::new(Mutex::new(TransferHistory::load())),
+            decisions: PendingDecisions::default(),
+            controls: TransferControls::default(),
+            stripes: StripeRegistry::default(),
+            diagnostics: DiagnosticsRegistry::default(),
+            limiter,
+            content_index: Arc::new(Mutex::new(ContentIndex::load())),
         }
     }
 
+    // 暂停、继续和取消命令直接使用，不需要锁住整个管理器
+    pub fn controls(&self) -> TransferControls {
+        self.controls.clone()
+    }
+
+    // 正在进行的传输当前使用的链路参数
+    pub fn diagnostics(&self) -> Vec<LinkDiagnostics> {
+        self.diagnostics.list()
+    }
+
+    // 界面答复传输请求时使用
+    pub fn decisions(&self) -> PendingDecisions {
+        self.decisions.clone()
+    }
+
+    // 设置变化或调整单个传输的限速时使用
+    pub fn limiter(&self) -> RateLimiter {
+        self.limiter.clone()
+    }
+
+    pub async fn get_history(&self) -> Vec<TransferRecord> {
+        self.history.lock().await.records().to_vec()
+    }
+
+    // 由调度器在名额空出后调用，control 在排队时已经注册，中断后重新排队时继续使用
     pub async fn send_files(
-        &mut self,
+        &self,
         target_device: Device,
         file_paths: Vec<String>,
+        control: &mut TransferControl,
         app_handle: tauri::AppHandle,
-    ) -> Result<(), String> {
-        for file_path in file_paths {
-            self.send_single_file(&target_device, &file_path, &app_handle)
-                .await?;
+    ) -> Result<(), SendError> {
+        let manifest = match self
+            .build_offer(
+                &file_paths,
+                &control.transfer_id,
+                &target_device.id,
+                &app_handle,
+            )
+            .await
+        {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                Self::emit_error(&app_handle, &target_device.name, &e);
+                Self::emit_finished(
+                    &app_handle,
+                    &control.transfer_id,
+                    "failed",
+                    ProgressSummary::default(),
+                );
+                return Err(SendError::Failed(e));
+            }
+        };
+
+        let local_device = self
+            .device_manager
+            .lock()
+            .await
+            .get_current_device()
+            .clone();
+
+        // 发送传输请求
+        let request = FileTransferRequest {
+            transfer_id: control.transfer_id.clone(),
+            total_size: manifest.total_size(),
+            files: manifest.entries.clone(),
+            sender_device: local_device,
+        };
+
+        Self::emit_started(&app_handle, &request, "send", &target_device);
+        let mut progress = ProgressTracker::new(
+            &app_handle,
+            &request.transfer_id,
+            &target_device.id,
+            &request.files,
+        );
+
+        // 连接中断时用同一个 transfer_id 重连，接收方会从断点继续
+        let mut attempt = 0;
+        let result = loop {
+            let result = self
+                .send_batch(
+                    &target_device,
+                    &request,
+                    &manifest,
+                    control,
+                    &mut progress,
+                    &app_handle,
+                )
+                .await;
+            let error = match result {
+                Ok(TransferOutcome::Interrupted(e)) => e,
+                // 对方可能正在重启，重连阶段连接失败也继续尝试
+                Err(e) if attempt > 0 => e,
+                other => break other,
+            };
+            if attempt == MAX_RESUME_ATTEMPTS {
+                break Err(error);
+            }
+            attempt += 1;
+            log::warn!(
+                "Transfer {} interrupted ({}), reconnecting ({}/{})",
+                request.transfer_id,
+                error,
+                attempt,
+                MAX_RESUME_ATTEMPTS
+            );
+            time::sleep(RESUME_RETRY_DELAY * attempt).await;
+            // 暂停期间不重连
+            if !control.wait_until_running().await {
+                break Ok(TransferOutcome::Cancelled);
+            }
+        };
+
+        let finished = match &result {
+            Ok(TransferOutcome::Completed) => Some(("completed", String::new())),
+            Ok(TransferOutcome::Declined(message)) => Some(("declined", message.clone())),
+            Ok(TransferOutcome::ChecksumMismatch(files)) => {
+                Some((ERROR_CHECKSUM_MISMATCH, Self::checksum_message(files)))
+            }
+            Ok(TransferOutcome::LinksFailed(links)) => Some(("failed", Self::links_message(links))),
+            Ok(TransferOutcome::Cancelled) => Some(("cancelled", TRANSFER_CANCELLED.to_string())),
+            // 中断的任务由调度器重新排队，还没有结束，不记录历史也不通知界面传输结束
+            Ok(TransferOutcome::Interrupted(_)) | Err(_) => None,
+        };
+        if let Some((status, message)) = finished {
+            Self::record_history(
+                &self.history,
+                &request,
+                "send",
+                &target_device,
+                true,
+                status,
+                &message,
+            )
+            .await;
+            Self::emit_finished(&app_handle, &request.transfer_id, status, progress.finish());
+        }
+
+        match result {
+            Ok(TransferOutcome::Completed) | Ok(TransferOutcome::Cancelled) => Ok(()),
+            Ok(TransferOutcome::Declined(message)) => {
+                let e = format!("Transfer rejected: {}", message);
+                Self::emit_error(&app_handle, &target_device.name, &e);
+                Err(SendError::Failed(e))
+            }
+            Ok(TransferOutcome::ChecksumMismatch(files)) => {
+                let e = Self::checksum_message(&files);
+                Self::emit_error(&app_handle, &target_device.name, &e);
+                Err(SendError::Failed(e))
+            }
+            Ok(TransferOutcome::LinksFailed(links)) => {
+                let e = Self::links_message(&links);
+                Self::emit_error(&app_handle, &target_device.name, &e);
+                Err(SendError::Failed(e))
+            }
+            Ok(TransferOutcome::Interrupted(e)) | Err(e) => {
+                Self::emit_error(&app_handle, &target_device.name, &e);
+                Err(SendError::Interrupted(e))
+            }
         }
-        Ok(())
     }
 
-    async fn send_single_file(
+    // 构建文件清单，文件夹会被递归展开，并附上每个文件的校验和和需要保留的扩展属性
+    // 连接之前计算所有文件的校验和，文件多或文件大时需要一段时间，期间显示正在计算的文件
+    async fn build_offer(
         &self,
-        target_device: &Device,
-        file_path: &str,
+        file_paths: &[String],
+        transfer_id: &str,
+        peer_id: &str,
         app_handle: &tauri::AppHandle,
-    ) -> Result<(), String> {
-        let path = Path::new(file_path);
-        let file_name = path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .ok_or("Invalid file name")?
-            .to_string();
-
-        // 获取文件大小
-        let metadata = fs::metadata(file_path)
-            .await
-            .map_err(|e| format!("Failed to get file metadata: {}", e))?;
-        let file_size = metadata.len();
+    ) -> Result<Manifest, String> {
+        let (ignore_patterns, follow_symlinks, xattrs) = {
+            let settings = self.settings.lock().await;
+            (
+                settings.ignore_patterns.clone(),
+                settings.follow_symlinks,
+                settings.preserve_xattrs,
+            )
+        };
+        let mut manifest =
+            manifest::build_manifest(file_paths, &ignore_patterns, follow_symlinks).await?;
+        if manifest.entries.is_empty() {
+            return Err("No files to send".to_string());
+        }
+
+        let mut progress =
+            ProgressTracker::new(app_handle, transfer_id, peer_id, &manifest.entries);
+        for (index, (entry, source)) in manifest
+            .entries
+            .iter_mut()
+            .zip(&manifest.sources)
+            .enumerate()
+        {
+            if entry.kind == EntryKind::File {
+                progress.start_hashing(index as u32);
+                entry.sha256 = Some(dedup::cached_hash(&self.content_index, source).await?);
+                if xattrs {
+                    entry.xattrs = attributes::read_xattrs(source);
+                }
+            }
+        }
+        self.content_index.lock().await.save();
+        Ok(manifest)
+    }
+
+    fn checksum_message(files: &[String]) -> String {
+        format!(
+            "Checksum mismatch after {} retries: {}",
+            MAX_CHECKSUM_RETRIES,
+            files.join(", ")
+        )
+    }
 
+    fn links_message(links: &[String]) -> String {
+        format!("Failed to create links: {}", links.join(", "))
+    }
+
+    async fn record_history(
+        history: &Mutex<TransferHistory>,
+        request: &FileTransferRequest,
+        direction: &str,
+        peer: &Device,
+        verified: bool,
+        status: &str,
+        message: &str,
+    ) {
+        let record = TransferRecord {
+            transfer_id: request.transfer_id.clone(),
+            direction: direction.to_string(),
+            peer: peer.clone(),
+            verified,
+            file_count: request.files.len(),
+            total_size: request.total_size,
+            status: status.to_string(),
+            message: message.to_string(),
+            timestamp: chrono::Local::now(),
+        };
+        history.lock().await.add(record);
+    }
+
+    async fn send_batch(
+        &self,
+        target_device: &Device,
+        request: &FileTransferRequest,
+        manifest: &Manifest,
+        control: &mut TransferControl,
+        progress: &mut ProgressTracker,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<TransferOutcome, String> {
         // 连接到目标设备
         let target_addr = format!("{}:{}", target_device.ip, self.transfer_port);
         let mut stream = TcpStream::connect(&target_addr)
             .await
             .map_err(|e| format!("Failed to connect to {}: {}", target_addr, e))?;
+        let socket_options = SocketOptions::from_settings(&*self.settings.lock().await);
+        socket_options.apply(&stream);
 
-        // 发送传输请求
-        let request = FileTransferRequest {
-            file_name: file_name.clone(),
-            file_size,
-            sender_device: target_device.clone(), // 这里应该是当前设备信息
-        };
+        let (session, rtt) = Self::client_handshake(&mut stream).await?;
+        log::info!(
+            "Negotiated session with {} (RTT {:?}): {:?}",
+            target_addr,
+            rtt,
+            session
+        );
+        // 旧版本的接收方无法解析链接，发送前就报错，不让对方看到无法接受的请求
+        if manifest.has_links() && !session.features.iter().any(|f| f == FEATURE_LINKS) {
+            return Err(
+                "Receiver does not support links, enable following symlinks or update it"
+                    .to_string(),
+            );
+        }
 
-        let request_json = serde_json::to_string(&request)
-            .map_err(|e| format!("Failed to serialize request: {}", e))?;
+        let throttle = self
+            .limiter
+            .throttle(&request.transfer_id, &target_device.id);
+        let mut conn = Connection::new(stream);
+        conn.set_throttle(throttle.clone());
+        conn.send(&Frame::json(FrameType::Offer, request)?).await?;
 
-        stream
-            .write_all(request_json.as_bytes())
-            .await
-            .map_err(|e| format!("Failed to send request: {}", e))?;
+        // 读取响应
+        let response: FileTransferResponse =
+            conn.recv().await?.expect(FrameType::Accept)?.parse()?;
 
-        stream
-            .write_all(b"\n")
-            .await
-            .map_err(|e| format!("Failed to send newline: {}", e))?;
+        if !response.accepted {
+            return Ok(TransferOutcome::Declined(response.message));
+        }
 
-        // 读取响应
-        let mut response_buffer = vec![0; 1024];
-        let bytes_read = stream
-            .read(&mut response_buffer)
+        let plan = SendPlan::new(response, &session);
+        let mut stripes = self
+            .connect_stripes(&target_addr, &session, &socket_options, request)
+            .await;
+        stripes.set_throttle(&throttle);
+        let tuning = LinkTuning::new(
+            app_handle,
+            &self.diagnostics,
+            &request.transfer_id,
+            "send",
+            conn.stream(),
+            session.chunk_size as usize,
+            rtt,
+        );
+        let mut link = TransferLink::new(&mut conn, control, progress, stripes, tuning, &session);
+        let result =
+            Self::stream_files(&mut link, &session, request, manifest, plan, app_handle).await;
+        match result {
+            Ok(outcome) => Ok(outcome),
+            // 对端已经通过 Control 帧得知取消
+            Err(_) if control.state() == ControlState::Cancelled => Ok(TransferOutcome::Cancelled),
+            Err(e) => {
+                conn.send_error(ERROR_INTERNAL, &e).await;
+                Ok(TransferOutcome::Interrupted(e))
+            }
+        }
+    }
+
+    // 有大文件时建立并行数据连接，失败时退回单连接发送
+    async fn connect_stripes(
+        &self,
+        target_addr: &str,
+        session: &SessionParams,
+        socket_options: &SocketOptions,
+        request: &FileTransferRequest,
+    ) -> Stripes {
+        let streams = (session.streams as usize).min(self.settings.lock().await.parallel_streams);
+        let has_large = request
+            .files
+            .iter()
+            .any(|entry| entry.kind == EntryKind::File && entry.size >= STRIPE_MIN_SIZE);
+        if streams <= 1 || !has_large {
+            return Stripes::default();
+        }
+
+        match Stripes::connect(
+            target_addr,
+            &request.transfer_id,
+            streams,
+            session,
+            socket_options,
+        )
+        .await
+        {
+            Ok(stripes) => stripes,
+            Err(e) => {
+                log::warn!("Falling back to a single connection: {}", e);
+                Stripes::default()
+            }
+        }
+    }
+
+    // 在同一个连接上依次发送清单中的所有文件
+    async fn stream_files(
+        link: &mut TransferLink<'_>,
+        session: &SessionParams,
+        request: &FileTransferRequest,
+        manifest: &Manifest,
+        mut plan: SendPlan,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<TransferOutcome, String> {
+        // 按协商的上限分配，每次实际读取的大小由 link.tuning 决定
+        let mut buffer = vec![0; session.chunk_size as usize];
+        let mut expected_bytes = 0u64;
+        let mut pending = Vec::new();
+
+        for (index, entry) in manifest.entries.iter().enumerate() {
+            if entry.kind != EntryKind::File {
+                continue;
+            }
+            if let Some(status) = plan.skip.get(&(index as u32)) {
+                link.progress.complete_file(index as u32, status, None);
+                continue;
+            }
+            pending.push(index as u32);
+            expected_bytes += entry.size;
+        }
+
+        // 接收方校验失败的文件会在 Ack 中列出，整批发送完后再重传
+        let mut batch = BatchWriter::new(session.chunk_size as usize, link.codec);
+        for _ in 0..=MAX_CHECKSUM_RETRIES {
+            for &index in &pending {
+                let entry = &manifest.entries[index as usize];
+                let source = &manifest.sources[index as usize];
+                if link.batched(entry) {
+                    Self::add_to_batch(link, &mut batch, index, entry, source, app_handle).await?;
+                    continue;
+                }
+                Self::send_batch_frame(link, &mut batch, app_handle).await?;
+                // 断点和差异只在第一次发送时使用，重传时从头发送
+                if plan.delta.remove(&index) {
+                    Self::send_delta(link, index, entry, source, app_handle).await?;
+                    continue;
+                }
+                let resume = plan.resume.remove(&index);
+                Self::send_file_data(link, index, entry, source, resume, &mut buffer, app_handle)
+                    .await?;
+            }
+            Self::send_batch_frame(link, &mut batch, app_handle).await?;
+
+            let ack: AckMessage = link
+                .next_frame(app_handle)
+                .await?
+                .expect(FrameType::Ack)?
+                .parse()?;
+
+            if !ack.retry.is_empty() {
+                if ack.retry.iter().any(|index| !pending.contains(index)) {
+                    return Err("Receiver requested retry of a file that was not sent".to_string());
+                }
+                log::warn!("Checksum mismatch, resending {} files", ack.retry.len());
+                pending = ack.retry;
+                continue;
+            }
+
+            link.conn
+                .send(&Frame::new(FrameType::Close, Vec::new()))
+                .await?;
+
+            if !ack.failed.is_empty() {
+                // 普通文件是校验失败，其余是接收方拒绝或无法创建的链接
+                let (files, links): (Vec<&FileEntry>, Vec<&FileEntry>) = ack
+                    .failed
+                    .iter()
+                    .filter_map(|index| request.files.get(*index as usize))
+                    .partition(|entry| entry.kind == EntryKind::File);
+                let paths = |entries: Vec<&FileEntry>| {
+                    entries
+                        .into_iter()
+                        .map(|entry| entry.relative_path.clone())
+                        .collect()
+                };
+                if !files.is_empty() {
+                    return Ok(TransferOutcome::ChecksumMismatch(paths(files)));
+                }
+                return Ok(TransferOutcome::LinksFailed(paths(links)));
+            }
+
+            // 等待接收方确认已写入全部数据
+            if ack.files_received as usize != request.files.len()
+                || ack.bytes_received != expected_bytes
+            {
+                return Err(format!(
+                    "Receiver reported {} of {} files ({} of {} bytes)",
+                    ack.files_received,
+                    request.files.len(),
+                    ack.bytes_received,
+                    expected_bytes
+                ));
+            }
+
+            return Ok(TransferOutcome::Completed);
+        }
+
+        Err("Receiver requested too many retries".to_string())
+    }
+
+    // 小文件整个读出后加入包中，包满时发送
+    async fn add_to_batch(
+        link: &mut TransferLink<'_>,
+        batch: &mut BatchWriter,
+        index: u32,
+        entry: &FileEntry,
+        source: &Path,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(), String> {
+        let data = fs::read(source)
             .await
-            .map_err(|e| format!("Failed to read response: {}", e))?;
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        if data.len() as u64 != entry.size {
+            return Err(format!(
+                "File changed during transfer: {}",
+                source.display()
+            ));
+        }
 
-        let response_str = String::from_utf8_lossy(&response_buffer[..bytes_read]);
-        let response: FileTransferResponse = serde_json::from_str(&response_str)
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        batch.push(index, &data);
+        if batch.is_full() {
+            Self::send_batch_frame(link, batch, app_handle).await?;
+        }
+        Ok(())
+    }
 
-        if !response.accepted {
-            return Err(format!("Transfer rejected: {}", response.message));
+    // 发送已打包的文件，包为空时什么也不做
+    async fn send_batch_frame(
+        link: &mut TransferLink<'_>,
+        batch: &mut BatchWriter,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(), String> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        link.wait_if_paused(app_handle).await?;
+
+        let packed = batch.take();
+        link.conn.send(&packed.frame).await?;
+        link.conn.throttle(packed.frame.payload.len()).await;
+        link.progress
+            .advance_compressed(packed.bytes, packed.wire_bytes);
+        link.tuning.record(packed.bytes);
+        for index in packed.files {
+            link.progress.complete_batched(index);
         }
+        Ok(())
+    }
 
-        // 开始传输文件
-        let mut file = fs::File::open(file_path)
+    async fn send_file_data(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        source: &Path,
+        resume: Option<ResumeOffset>,
+        buffer: &mut [u8],
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(), String> {
+        let mut file = fs::File::open(source)
             .await
             .map_err(|e| format!("Failed to open file: {}", e))?;
 
-        let mut buffer = vec![0; 8192]; // 8KB 缓冲区
+        let mut hasher = Sha256::new();
         let mut bytes_sent = 0u64;
+        // 接收方保留着不一致的旧前缀时不能跳过空洞
+        let mut restarted = false;
 
-        // 发送初始进度
-        let progress = TransferProgress {
-            file_name: file_name.clone(),
-            progress: 0.0,
-            status: "sending".to_string(),
+        // 接收方的前缀与本地文件一致时才续传，否则从头发送
+        if let Some(resume) = resume.filter(|r| r.offset <= entry.size) {
+            match Self::hash_prefix(&mut file, resume.offset, buffer).await? {
+                Some(prefix) if format!("{:x}", prefix.clone().finalize()) == resume.sha256 => {
+                    log::info!(
+                        "Resuming {} at offset {}",
+                        entry.relative_path,
+                        resume.offset
+                    );
+                    hasher = prefix;
+                    bytes_sent = resume.offset;
+                }
+                _ => {
+                    log::warn!(
+                        "Receiver's copy of {} differs, sending from the start",
+                        entry.relative_path
+                    );
+                    file.seek(SeekFrom::Start(0))
+                        .await
+                        .map_err(|e| format!("Failed to seek file: {}", e))?;
+                    restarted = true;
+                }
+            }
+        }
+
+        link.progress.start_file(index, bytes_sent, "sending");
+
+        // 稀疏文件只发送有数据的区间，否则整个文件作为一个区间
+        let extents = if link.sparse && !restarted {
+            sparse::data_extents(source, entry.size)
+        } else {
+            None
+        };
+        let extents = match extents {
+            Some(extents) => {
+                log::info!(
+                    "Sending {} as a sparse file: {} of {} bytes in {} extents",
+                    entry.relative_path,
+                    extents.iter().map(|(start, end)| end - start).sum::<u64>(),
+                    entry.size,
+                    extents.len()
+                );
+                extents
+            }
+            // 大文件拆分到多个连接并行发送
+            None if link.stripes.available() > 1
+                && entry.size - bytes_sent >= STRIPE_MIN_SIZE
+                && link.tuning.wants_stripes() =>
+            {
+                return Self::send_striped(link, index, entry, source, bytes_sent, app_handle)
+                    .await;
+            }
+            None => vec![(bytes_sent, entry.size)],
         };
-        let _ = app_handle.emit("transfer-progress", &progress);
 
-        loop {
-            let bytes_read = file
-                .read(&mut buffer)
-                .await
-                .map_err(|e| format!("Failed to read file: {}", e))?;
+        let mut compressor = link.codec.map(|codec| Compressor::new(codec, source));
+        // 不需要压缩时由内核直接发送，不读出文件内容，接收方用清单中的整体校验和核对
+        let manifest_sha256 = entry
+            .sha256
+            .as_ref()
+            .filter(|_| link.sendfile && compressor.as_ref().is_none_or(Compressor::skips_file));
+        for (start, end) in extents {
+            if start > bytes_sent {
+                Self::skip_hole(link, &mut hasher, bytes_sent, start);
+                bytes_sent = start;
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(|e| format!("Failed to seek file: {}", e))?;
+            }
+            while bytes_sent < end {
+                // 处理暂停和取消，暂停时在这里等待
+                link.wait_if_paused(app_handle).await?;
 
-            if bytes_read == 0 {
-                break; // 文件读取完成
+                let chunk_size = link.tuning.chunk_size().min(buffer.len());
+                let to_read = (end - bytes_sent).min(chunk_size as u64) as usize;
+                let (bytes_read, wire) = if manifest_sha256.is_some() {
+                    let wire = link
+                        .conn
+                        .send_file_range(index, bytes_sent, &file, to_read)
+                        .await?;
+                    (to_read, wire)
+                } else {
+                    let bytes_read = file
+                        .read(&mut buffer[..to_read])
+                        .await
+                        .map_err(|e| format!("Failed to read file: {}", e))?;
+
+                    if bytes_read == 0 {
+                        return Err(format!(
+                            "File changed during transfer: {}",
+                            source.display()
+                        ));
+                    }
+
+                    hasher.update(&buffer[..bytes_read]);
+                    let wire = link
+                        .conn
+                        .send_file_data(
+                            index,
+                            bytes_sent,
+                            &file,
+                            &buffer[..bytes_read],
+                            link.sendfile,
+                            compressor.as_mut(),
+                        )
+                        .await?;
+                    (bytes_read, wire)
+                };
+
+                bytes_sent += bytes_read as u64;
+                link.progress
+                    .advance_compressed(bytes_read as u64, wire as u64);
+                link.tuning.record(bytes_read as u64);
             }
+        }
+        // 结尾的空洞由接收方按结尾帧的大小补齐
+        if bytes_sent < entry.size {
+            Self::skip_hole(link, &mut hasher, bytes_sent, entry.size);
+            bytes_sent = entry.size;
+        }
 
-            stream
-                .write_all(&buffer[..bytes_read])
-                .await
-                .map_err(|e| format!("Failed to send file data: {}", e))?;
+        let file_end = FileEndMessage {
+            file_index: index,
+            size: bytes_sent,
+            sha256: match manifest_sha256 {
+                Some(sha256) => sha256.clone(),
+                None => format!("{:x}", hasher.finalize()),
+            },
+            ranges: Vec::new(),
+        };
+        link.conn
+            .send(&Frame::json(FrameType::FileEnd, &file_end)?)
+            .await?;
+
+        link.progress.complete_file(index, "completed", None);
 
-            bytes_sent += bytes_read as u64;
-            let progress_percent = (bytes_sent as f64 / file_size as f64) * 100.0;
+        Ok(())
+    }
+
+    // 空洞不发送，按全零计入校验和与进度
+    fn skip_hole(link: &mut TransferLink<'_>, hasher: &mut Sha256, from: u64, to: u64) {
+        sparse::hash_zeros(hasher, to - from);
+        link.progress.advance_hole(to - from);
+    }
+
+    // 接收方到这个文件时发来旧版本的块签名，之后只发送变化的部分和引用的块号
+    async fn send_delta(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        source: &Path,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(), String> {
+        let frame = link
+            .next_frame(app_handle)
+            .await?
+            .expect(FrameType::Signature)?;
+        let signature = Signature::parse(&frame)?;
+        if signature.file_index != index {
+            return Err(format!(
+                "Unexpected signature for file {}, expected file {}",
+                signature.file_index, index
+            ));
+        }
 
-            // 发送进度更新
-            let progress = TransferProgress {
-                file_name: file_name.clone(),
-                progress: progress_percent,
-                status: "sending".to_string(),
+        let mut encoder = DeltaEncoder::new(source, signature).await?;
+        link.progress.start_file(index, 0, "sending");
+        let mut wire_bytes = 0u64;
+        loop {
+            link.wait_if_paused(app_handle).await?;
+            let Some(chunk) = encoder.next_chunk(link.tuning.chunk_size()).await? else {
+                break;
             };
-            let _ = app_handle.emit("transfer-progress", &progress);
+            let wire = chunk.frame.payload.len();
+            link.conn.send(&chunk.frame).await?;
+            link.conn.throttle(wire).await;
+            link.progress
+                .advance_delta(chunk.bytes, chunk.reused, wire as u64);
+            link.tuning.record(wire as u64);
+            wire_bytes += wire as u64;
+        }
+
+        let (size, sha256, reused) = encoder.finish();
+        if size != entry.size {
+            return Err(format!(
+                "File changed during transfer: {}",
+                source.display()
+            ));
+        }
+        log::info!(
+            "Sent {} as a delta: {} of {} bytes reused, {} bytes sent",
+            entry.relative_path,
+            reused,
+            size,
+            wire_bytes
+        );
+
+        let file_end = FileEndMessage {
+            file_index: index,
+            size,
+            sha256,
+            ranges: Vec::new(),
+        };
+        link.conn
+            .send(&Frame::json(FrameType::FileEnd, &file_end)?)
+            .await?;
+
+        link.progress.complete_file(index, "completed", None);
+        Ok(())
+    }
+
+    async fn send_striped(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        source: &Path,
+        start: u64,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(), String> {
+        let ranges = stripe::split_ranges(start, entry.size, link.stripes.available());
+        // 不需要压缩时各区间不读出内容也不计算校验和，按清单中的整体校验和核对
+        let mut options = link.stripes.send_options();
+        let skips_compression = options
+            .codec
+            .is_none_or(|codec| Compressor::new(codec, source).skips_file());
+        let manifest_sha256 = entry
+            .sha256
+            .clone()
+            .filter(|_| options.sendfile && skips_compression);
+        options.whole_file = manifest_sha256.is_some();
+        let stripe = StripeMessage {
+            file_index: index,
+            ranges: ranges.clone(),
+            whole_file: manifest_sha256.is_some(),
+        };
+        link.conn
+            .send(&Frame::json(FrameType::Stripe, &stripe)?)
+            .await?;
+
+        let conns = link.stripes.take(ranges.len()).await?;
+        let counters: Vec<_> = ranges
+            .iter()
+            .map(|_| Arc::new(RangeProgress::default()))
+            .collect();
+        let mut workers = JoinSet::new();
+        for (i, (conn, range)) in conns.into_iter().zip(ranges).enumerate() {
+            let task = stripe::send_range(
+                conn,
+                index,
+                source.to_path_buf(),
+                range,
+                options,
+                link.control.subscribe(),
+                counters[i].clone(),
+            );
+            workers.spawn(async move { (i, task.await) });
+        }
+
+        let (results, pending) = link.run_stripes(workers, &counters, app_handle).await?;
+        // 发送期间接收方只会发来控制帧或错误
+        if let Some(frame) = pending {
+            frame.expect(FrameType::Control)?;
         }
+        let (conns, ranges): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+        link.stripes.restore(conns);
 
-        // 传输完成
-        let progress = TransferProgress {
-            file_name: file_name.clone(),
-            progress: 100.0,
-            status: "completed".to_string(),
+        let file_end = match manifest_sha256 {
+            Some(sha256) => FileEndMessage {
+                file_index: index,
+                size: entry.size,
+                sha256,
+                ranges: Vec::new(),
+            },
+            None => FileEndMessage {
+                file_index: index,
+                size: entry.size,
+                sha256: String::new(),
+                ranges,
+            },
         };
-        let _ = app_handle.emit("transfer-progress", &progress);
+        link.conn
+            .send(&Frame::json(FrameType::FileEnd, &file_end)?)
+            .await?;
 
+        link.progress.complete_file(index, "completed", None);
         Ok(())
     }
 
+    // 计算文件前 len 字节的 SHA-256，文件不足 len 字节时返回 None
+    async fn hash_prefix(
+        file: &mut fs::File,
+        len: u64,
+        buffer: &mut [u8],
+    ) -> Result<Option<Sha256>, String> {
+        let mut hasher = Sha256::new();
+        let mut remaining = len;
+        while remaining > 0 {
+            let to_read = remaining.min(buffer.len() as u64) as usize;
+            let bytes_read = file
+                .read(&mut buffer[..to_read])
+                .await
+                .map_err(|e| format!("Failed to read file: {}", e))?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+            hasher.update(&buffer[..bytes_read]);
+            remaining -= bytes_read as u64;
+        }
+        Ok(Some(hasher))
+    }
+
     pub async fn start_file_server(&self, app_handle: tauri::AppHandle) -> Result<(), String> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", self.transfer_port))
             .await
             .map_err(|e| format!("Failed to bind to port {}: {}", self.transfer_port, e))?;
+        SocketOptions::from_settings(&*self.settings.lock().await).apply_listener(&listener);
 
         log::info!(
             "File transfer server listening on port {}",
             self.transfer_port
         );
 
+        let context = ReceiveContext {
+            app_handle,
+            settings: self.settings.clone(),
+            device_manager: self.device_manager.clone(),
+            history: self.history.clone(),
+            decisions: self.decisions.clone(),
+            controls: self.controls.clone(),
+            stripes: self.stripes.clone(),
+            diagnostics: self.diagnostics.clone(),
+            content_index: self.content_index.clone(),
+        };
+
         tokio::spawn(async move {
             loop {
                 match listener.accept().await {
                     Ok((stream, _)) => {
-                        let app_handle_clone = app_handle.clone();
+                        let context = context.clone();
                         tokio::spawn(async move {
-                            if let Err(e) =
-                                Self::handle_incoming_transfer(stream, app_handle_clone).await
-                            {
+                            if let Err(e) = Self::handle_incoming_transfer(stream, context).await {
                                 log::error!("Failed to handle incoming transfer: {}", e);
                             }
                         });
@@ -197,100 +1208,1903 @@ impl FileTransferManager {
         Ok(())
     }
 
-    async fn handle_incoming_transfer(
-        mut stream: TcpStream,
-        app_handle: tauri::AppHandle,
-    ) -> Result<(), String> {
-        // 读取传输请求
-        let mut request_buffer = vec![0; 1024];
-        let bytes_read = stream
-            .read(&mut request_buffer)
-            .await
-            .map_err(|e| format!("Failed to read request: {}", e))?;
+    fn emit_error(app_handle: &tauri::AppHandle, peer: &str, message: &str) {
+        let error = TransferError {
+            peer: peer.to_string(),
+            message: message.to_string(),
+        };
+        let _ = app_handle.emit("transfer-error", &error);
+    }
 
-        let request_str = String::from_utf8_lossy(&request_buffer[..bytes_read]);
-        let lines: Vec<&str> = request_str.split('\n').collect();
+    // 发送方: 交换协议头，发送 Hello 并取得接收方选定的会话参数，
+    // 同时用 Hello 的往返估算链路的往返时间
+    async fn client_handshake(stream: &mut TcpStream) -> Result<(SessionParams, Duration), String> {
+        protocol::write_preamble(stream).await?;
+        protocol::read_preamble(stream).await?;
 
-        if lines.is_empty() {
-            return Err("Empty request".to_string());
-        }
+        let local = HelloMessage::local();
+        let sent = Instant::now();
+        protocol::write_frame(stream, &Frame::json(FrameType::Hello, &local)?).await?;
 
-        let request: FileTransferRequest = serde_json::from_str(lines[0])
-            .map_err(|e| format!("Failed to parse request: {}", e))?;
+        let reply: HelloMessage = protocol::read_frame(stream)
+            .await?
+            .expect(FrameType::Hello)?
+            .parse()?;
+        let rtt = sent.elapsed();
+        let session = reply
+            .session
+            .ok_or("Peer did not return negotiated session parameters")?;
+        protocol::validate_session(&local, &session)?;
 
-        // 自动接受传输（在实际应用中，这里应该询问用户）
-        let response = FileTransferResponse {
-            accepted: true,
-            message: "Transfer accepted".to_string(),
+        Ok((session, rtt))
+    }
+
+    // 接收方: 根据双方能力协商会话参数，不兼容时通知对端
+    async fn server_handshake(
+        stream: &mut TcpStream,
+        hello: Frame,
+    ) -> Result<SessionParams, String> {
+        let peer: HelloMessage = hello.expect(FrameType::Hello)?.parse()?;
+
+        let mut local = HelloMessage::local();
+        let session = match protocol::negotiate(&local, &peer) {
+            Ok(session) => session,
+            Err(e) => {
+                protocol::send_error(stream, ERROR_INCOMPATIBLE, &e).await;
+                return Err(e);
+            }
         };
 
-        let response_json = serde_json::to_string(&response)
-            .map_err(|e| format!("Failed to serialize response: {}", e))?;
+        local.session = Some(session.clone());
+        protocol::write_frame(stream, &Frame::json(FrameType::Hello, &local)?).await?;
 
-        stream
-            .write_all(response_json.as_bytes())
-            .await
-            .map_err(|e| format!("Failed to send response: {}", e))?;
+        Ok(session)
+    }
+
+    async fn handle_incoming_transfer(
+        mut stream: TcpStream,
+        context: ReceiveContext,
+    ) -> Result<(), String> {
+        let app_handle = &context.app_handle;
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
 
-        // 接收文件
-        let downloads_dir = dirs::download_dir().ok_or("Failed to get downloads directory")?;
-        let file_path = downloads_dir.join(&request.file_name);
+        SocketOptions::from_settings(&*context.settings.lock().await).apply(&stream);
+        protocol::write_preamble(&mut stream).await?;
+        protocol::read_preamble(&mut stream).await?;
 
-        let mut file = fs::File::create(&file_path)
+        // 并行数据连接交给对应的传输，不单独握手
+        let first = protocol::read_frame(&mut stream).await?;
+        if first.frame_type == FrameType::Join {
+            let join: JoinMessage = first.parse()?;
+            return context.stripes.join(join, &peer, stream).await;
+        }
+
+        // 握手失败时已经通知过对端，这里只需要通知界面
+        let session = match Self::server_handshake(&mut stream, first).await {
+            Ok(session) => session,
+            Err(e) => {
+                Self::emit_error(app_handle, &peer, &e);
+                return Err(e);
+            }
+        };
+        log::info!("Negotiated session with {}: {:?}", peer, session);
+        let replied = Instant::now();
+
+        let mut conn = Connection::new(stream);
+        let result = Self::receive_transfer(&mut conn, &context, &session, &peer, replied).await;
+        if let Err(e) = &result {
+            conn.send_error(ERROR_INTERNAL, e).await;
+            Self::emit_error(app_handle, &peer, e);
+        }
+        result
+    }
+
+    fn notify(app_handle: &tauri::AppHandle, title: &str, body: &str) {
+        let _ = app_handle
+            .notification()
+            .builder()
+            .title(title)
+            .body(body)
+            .show();
+    }
+
+    // 发送方自报的身份不可信，用连接的真实地址和已发现的设备进行校验
+    async fn verify_sender(
+        context: &ReceiveContext,
+        claimed: &Device,
+        peer_ip: &str,
+    ) -> (Device, bool) {
+        let mut sender = claimed.clone();
+        let mut verified = false;
+
+        match context
+            .device_manager
+            .lock()
             .await
-            .map_err(|e| format!("Failed to create file: {}", e))?;
+            .get_discovered_device(&claimed.id)
+        {
+            Some(known) if known.ip == peer_ip => {
+                // 名称等信息以发现时记录的为准
+                sender = known.clone();
+                verified = true;
+            }
+            Some(known) => log::warn!(
+                "Sender claims to be {} ({}) but connected from {}",
+                known.name,
+                known.ip,
+                peer_ip
+            ),
+            None if claimed.ip != peer_ip => log::warn!(
+                "Sender {} claims address {} but connected from {}",
+                claimed.name,
+                claimed.ip,
+                peer_ip
+            ),
+            None => {}
+        }
+
+        sender.ip = peer_ip.to_string();
+        (sender, verified)
+    }
+
+    fn emit_started(
+        app_handle: &tauri::AppHandle,
+        request: &FileTransferRequest,
+        direction: &str,
+        peer: &Device,
+    ) {
+        let started = TransferStarted {
+            transfer_id: request.transfer_id.clone(),
+            direction: direction.to_string(),
+            peer: peer.clone(),
+            file_count: request.files.len(),
+            total_size: request.total_size,
+        };
+        let _ = app_handle.emit("transfer-started", &started);
+    }
+
+    pub fn emit_finished(
+        app_handle: &tauri::AppHandle,
+        transfer_id: &str,
+        status: &str,
+        summary: ProgressSummary,
+    ) {
+        log::info!(
+            "Transfer {} {}: {} bytes in {:.1}s, {} bytes on the wire (compression ratio {:.2})",
+            transfer_id,
+            status,
+            summary.bytes_done,
+            summary.elapsed_secs,
+            summary.wire_bytes,
+            summary.compression_ratio
+        );
+        let finished = TransferFinished {
+            transfer_id: transfer_id.to_string(),
+            status: status.to_string(),
+            summary,
+        };
+        let _ = app_handle.emit("transfer-finished", &finished);
+    }
+
+    // 通知界面并等待用户答复，超时视为拒绝
+    async fn wait_for_decision(
+        context: &ReceiveContext,
+        request: &FileTransferRequest,
+        sender: &Device,
+        verified: bool,
+    ) -> TransferDecision {
+        let decision_rx = context.decisions.register(&request.transfer_id).await;
+
+        let incoming = IncomingTransferRequest {
+            transfer_id: request.transfer_id.clone(),
+            sender: sender.clone(),
+            verified,
+            files: request.files.clone(),
+            file_count: request.files.len(),
+            total_size: request.total_size,
+        };
+        let _ = context
+            .app_handle
+            .emit("incoming-transfer-request", &incoming);
+        Self::notify(
+            &context.app_handle,
+            "LANTransfer",
+            &format!("{} 想要发送 {} 个项目", sender.name, request.files.len()),
+        );
+
+        let timeout = Duration::from_secs(context.settings.lock().await.accept_timeout_secs);
+        let decision = match time::timeout(timeout, decision_rx).await {
+            Ok(Ok(decision)) => decision,
+            _ => {
+                let expired = IncomingTransferExpired {
+                    transfer_id: request.transfer_id.clone(),
+                };
+                let _ = context
+                    .app_handle
+                    .emit("incoming-transfer-expired", &expired);
+                TransferDecision {
+                    accept: false,
+                    save_dir: None,
+                    collision_policy: None,
+                }
+            }
+        };
+
+        context.decisions.unregister(&request.transfer_id).await;
+        decision
+    }
+
+    async fn receive_transfer(
+        conn: &mut Connection,
+        context: &ReceiveContext,
+        session: &SessionParams,
+        peer_ip: &str,
+        replied: Instant,
+    ) -> Result<(), String> {
+        // 读取传输请求，发送方收到 Hello 后立即发送，间隔约为一个往返时间
+        let request: FileTransferRequest = conn.recv().await?.expect(FrameType::Offer)?.parse()?;
+        let rtt = replied.elapsed();
+
+        // 在询问用户之前拒绝包含非法路径的清单
+        for entry in &request.files {
+            sanitize::validate_relative_path(&entry.relative_path)?;
+        }
+
+        let (sender, verified) =
+            Self::verify_sender(context, &request.sender_device, peer_ip).await;
+
+        // 已验证的发送方重连续传之前已接受、清单完全相同的传输时不再询问用户
+        let resumed = if verified {
+            resume::find(
+                &request.transfer_id,
+                &request.sender_device.id,
+                peer_ip,
+                &request.files,
+            )
+        } else {
+            None
+        };
+        let decision = match &resumed {
+            Some(state) => {
+                log::info!(
+                    "Resuming transfer {} from {}",
+                    request.transfer_id,
+                    sender.name
+                );
+                TransferDecision {
+                    accept: true,
+                    save_dir: Some(state.root.clone()),
+                    collision_policy: Some(state.collision_policy),
+                }
+            }
+            None => Self::wait_for_decision(context, &request, &sender, verified).await,
+        };
+        if !decision.accept {
+            let response = FileTransferResponse {
+                accepted: false,
+                message: "Transfer declined by receiver".to_string(),
+                skip: Vec::new(),
+                resume: Vec::new(),
+                identical: Vec::new(),
+                delta: Vec::new(),
+            };
+            conn.send(&Frame::json(FrameType::Accept, &response)?)
+                .await?;
+            log::info!(
+                "Transfer {} from {} declined",
+                request.transfer_id,
+                sender.name
+            );
+            Self::record_history(
+                &context.history,
+                &request,
+                "receive",
+                &sender,
+                verified,
+                "declined",
+                &response.message,
+            )
+            .await;
+            return Ok(());
+        }
+
+        let mut control = context.controls.register(&request.transfer_id).await;
+        let mut progress = ProgressTracker::new(
+            &context.app_handle,
+            &request.transfer_id,
+            &request.sender_device.id,
+            &request.files,
+        );
+        let stripes = if session.streams > 1 {
+            context
+                .stripes
+                .register(&request.transfer_id, peer_ip, session.streams as usize)
+                .await
+        } else {
+            Stripes::default()
+        };
+        let tuning = LinkTuning::new(
+            &context.app_handle,
+            &context.diagnostics,
+            &request.transfer_id,
+            "receive",
+            conn.stream(),
+            session.chunk_size as usize,
+            rtt,
+        );
+        let mut link =
+            TransferLink::new(conn, &mut control, &mut progress, stripes, tuning, session);
+        let result = Self::receive_files(
+            &mut link, context, session, &request, peer_ip, decision, resumed,
+        )
+        .await;
+        context.controls.unregister(&request.transfer_id).await;
+        context.stripes.unregister(&request.transfer_id).await;
+
+        let (status, message) = match &result {
+            Ok(TransferOutcome::ChecksumMismatch(files)) => {
+                (ERROR_CHECKSUM_MISMATCH, Self::checksum_message(files))
+            }
+            Ok(TransferOutcome::LinksFailed(links)) => ("failed", Self::links_message(links)),
+            Ok(TransferOutcome::Cancelled) => ("cancelled", TRANSFER_CANCELLED.to_string()),
+            Ok(_) => ("completed", String::new()),
+            Err(e) => ("failed", e.clone()),
+        };
+        Self::record_history(
+            &context.history,
+            &request,
+            "receive",
+            &sender,
+            verified,
+            status,
+            &message,
+        )
+        .await;
+        Self::emit_finished(
+            &context.app_handle,
+            &request.transfer_id,
+            status,
+            progress.finish(),
+        );
+
+        match result? {
+            TransferOutcome::ChecksumMismatch(_) | TransferOutcome::LinksFailed(_) => {
+                Self::emit_error(&context.app_handle, &sender.name, &message);
+            }
+            TransferOutcome::Cancelled => {}
+            _ => Self::notify(
+                &context.app_handle,
+                "LANTransfer",
+                &format!(
+                    "已收到来自 {} 的 {} 个项目",
+                    sender.name,
+                    request.files.len()
+                ),
+            ),
+        }
+        Ok(())
+    }
+
+    // 在接受之前确定每一项的保存位置，None 表示按重名策略跳过
+    fn plan_targets(
+        request: &FileTransferRequest,
+        root: &Path,
+        policy: CollisionPolicy,
+    ) -> Result<Vec<Option<PathBuf>>, String> {
+        let mut reserved = HashSet::new();
+        request
+            .files
+            .iter()
+            .map(|entry| {
+                let path = match entry.kind {
+                    EntryKind::Symlink | EntryKind::Hardlink => {
+                        sanitize::resolve_received_link(root, &entry.relative_path)?
+                    }
+                    _ => sanitize::resolve_received_path(root, &entry.relative_path)?,
+                };
+                Ok(match entry.kind {
+                    // 已存在的目录直接合并
+                    EntryKind::Directory => Some(path),
+                    EntryKind::File | EntryKind::Symlink | EntryKind::Hardlink => {
+                        collision::resolve_target(&path, policy, entry.modified, &mut reserved)
+                    }
+                })
+            })
+            .collect()
+    }
+
+    async fn receive_files(
+        link: &mut TransferLink<'_>,
+        context: &ReceiveContext,
+        session: &SessionParams,
+        request: &FileTransferRequest,
+        peer_ip: &str,
+        decision: TransferDecision,
+        resumed: Option<ResumeState>,
+    ) -> Result<TransferOutcome, String> {
+        let app_handle = &context.app_handle;
+
+        let mut state = match resumed {
+            Some(state) => state,
+            None => {
+                let downloads_dir = match decision.save_dir {
+                    Some(dir) => dir,
+                    None => dirs::download_dir().ok_or("Failed to get downloads directory")?,
+                };
+                let policy = match decision.collision_policy {
+                    Some(policy) => policy,
+                    None => context.settings.lock().await.collision_policy,
+                };
+                let targets = Self::plan_targets(request, &downloads_dir, policy)?;
+                ResumeState::new(
+                    &request.transfer_id,
+                    &request.sender_device.id,
+                    peer_ip,
+                    &request.files,
+                    &downloads_dir,
+                    policy,
+                    targets,
+                )
+            }
+        };
 
-        let mut buffer = vec![0; 8192];
+        let finalizer = {
+            let settings = context.settings.lock().await;
+            Finalizer {
+                content_index: &context.content_index,
+                metadata: settings.preserve_metadata,
+                xattrs: settings.preserve_xattrs,
+            }
+        };
+        let identical = Self::find_identical(request, &mut state, &finalizer).await;
+        let mut starts = if session.features.iter().any(|f| f == FEATURE_RESUME) {
+            Self::prepare_resume(request, &mut state).await
+        } else {
+            state.partial.clear();
+            HashMap::new()
+        };
+        if session.features.iter().any(|f| f == FEATURE_DELTA) {
+            Self::find_delta_bases(request, &state, &mut starts).await;
+        }
+        Self::save_resume_state(&mut state);
+
+        let response = FileTransferResponse {
+            accepted: true,
+            message: "Transfer accepted".to_string(),
+            skip: state
+                .targets
+                .iter()
+                .enumerate()
+                .filter(|(index, target)| {
+                    target.is_none() || state.completed.contains(&(*index as u32))
+                })
+                .map(|(index, _)| index as u32)
+                .collect(),
+            resume: state
+                .partial
+                .iter()
+                .filter(|(index, _)| matches!(starts.get(index), Some(PartStart::Resume(..))))
+                .map(|(index, partial)| ResumeOffset {
+                    file_index: *index,
+                    offset: partial.offset,
+                    sha256: partial.sha256.clone(),
+                })
+                .collect(),
+            identical: identical.iter().copied().collect(),
+            delta: starts
+                .iter()
+                .filter(|(_, start)| matches!(start, PartStart::Delta(_)))
+                .map(|(index, _)| *index)
+                .collect(),
+        };
+
+        link.conn
+            .send(&Frame::json(FrameType::Accept, &response)?)
+            .await?;
+        Self::emit_started(app_handle, request, "receive", &request.sender_device);
+
+        let result = Self::receive_entries(
+            link, request, &mut state, starts, &identical, &finalizer, app_handle,
+        )
+        .await;
+        context.content_index.lock().await.save();
+        // 取消时不再保留临时文件
+        if result.is_err() && link.control.state() == ControlState::Cancelled {
+            for partial in state.partial.values() {
+                let _ = fs::remove_file(partfile::part_path(&partial.target)).await;
+            }
+            resume::remove(&request.transfer_id);
+            log::info!("Transfer {} cancelled", request.transfer_id);
+            return Ok(TransferOutcome::Cancelled);
+        }
+        result
+    }
+
+    async fn receive_entries(
+        link: &mut TransferLink<'_>,
+        request: &FileTransferRequest,
+        state: &mut ResumeState,
+        mut starts: HashMap<u32, PartStart>,
+        identical: &HashSet<u32>,
+        finalizer: &Finalizer<'_>,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<TransferOutcome, String> {
+        // 目录先创建好，之后按清单顺序接收文件，链接等所有文件收完再创建
+        let mut pending = Vec::new();
+        let mut link_entries = Vec::new();
+        for (index, (entry, target)) in request.files.iter().zip(&state.targets).enumerate() {
+            let Some(target) = target else {
+                log::info!("Skipping existing file: {}", entry.relative_path);
+                link.progress.complete_file(index as u32, "skipped", None);
+                continue;
+            };
+            if state.completed.contains(&(index as u32)) {
+                let status = if identical.contains(&(index as u32)) {
+                    "identical"
+                } else {
+                    "completed"
+                };
+                link.progress
+                    .complete_file(index as u32, status, Some(target));
+                continue;
+            }
+
+            match entry.kind {
+                EntryKind::Directory => {
+                    fs::create_dir_all(target)
+                        .await
+                        .map_err(|e| format!("Failed to create directory: {}", e))?;
+                }
+                EntryKind::File => pending.push((index as u32, target.clone())),
+                EntryKind::Symlink | EntryKind::Hardlink => {
+                    link_entries.push((index as u32, target.clone()))
+                }
+            }
+        }
+
+        // 与发送方一致，跳过的文件和之前已完成的文件不计入确认的字节数
         let mut bytes_received = 0u64;
+        let mut batch = VecDeque::new();
+        for round in 0..=MAX_CHECKSUM_RETRIES {
+            let mut mismatched = Vec::new();
+            for (index, target) in &pending {
+                let entry = &request.files[*index as usize];
+                let start = starts.remove(index);
+                let received = if link.batched(entry) {
+                    if batch.is_empty() {
+                        batch = Self::next_batch(link, app_handle).await?;
+                    }
+                    let file = batch.pop_front().ok_or("Received an empty batch")?;
+                    let received =
+                        Self::write_batched(link, *index, entry, target, file, state).await?;
+                    // 每个包只保存一次续传状态
+                    if batch.is_empty() {
+                        Self::save_resume_state(state);
+                    }
+                    received
+                } else if !batch.is_empty() {
+                    return Err(format!(
+                        "Batch contains unexpected files before {}",
+                        entry.relative_path
+                    ));
+                } else {
+                    Self::receive_file(link, *index, entry, target, start, state, app_handle)
+                        .await?
+                };
+                if received {
+                    bytes_received += entry.size;
+                    finalizer.finish(entry, target).await;
+                } else {
+                    mismatched.push((*index, target.clone()));
+                }
+            }
+            if !batch.is_empty() {
+                return Err("Batch contains files that were not expected".to_string());
+            }
+
+            let retry: Vec<u32> = mismatched.iter().map(|(index, _)| *index).collect();
+            let last_round = round == MAX_CHECKSUM_RETRIES;
+            if !retry.is_empty() && !last_round {
+                log::warn!(
+                    "Checksum mismatch in transfer {}, requesting {} files again",
+                    request.transfer_id,
+                    retry.len()
+                );
+                let ack = AckMessage {
+                    files_received: 0,
+                    bytes_received,
+                    retry,
+                    failed: Vec::new(),
+                };
+                link.conn.send(&Frame::json(FrameType::Ack, &ack)?).await?;
+                pending = mismatched;
+                continue;
+            }
+
+            let failed_links = Self::create_links(link, request, state, &link_entries).await;
+            let failed: Vec<u32> = retry.iter().chain(&failed_links).copied().collect();
+            let ack = AckMessage {
+                files_received: (request.files.len() - failed.len()) as u32,
+                bytes_received,
+                retry: Vec::new(),
+                failed,
+            };
+            link.conn.send(&Frame::json(FrameType::Ack, &ack)?).await?;
+            resume::remove(&request.transfer_id);
+            link.next_frame(app_handle)
+                .await?
+                .expect(FrameType::Close)?;
+
+            if !retry.is_empty() {
+                let files = retry
+                    .iter()
+                    .map(|index| request.files[*index as usize].relative_path.clone())
+                    .collect();
+                return Ok(TransferOutcome::ChecksumMismatch(files));
+            }
+            if !failed_links.is_empty() {
+                let links = failed_links
+                    .iter()
+                    .map(|index| request.files[*index as usize].relative_path.clone())
+                    .collect();
+                return Ok(TransferOutcome::LinksFailed(links));
+            }
+            break;
+        }
+
+        log::info!(
+            "Transfer {} finished: {} files, {} bytes",
+            request.transfer_id,
+            request.files.len(),
+            bytes_received
+        );
+
+        Ok(TransferOutcome::Completed)
+    }
+
+    // 所有文件写完后创建链接，之后不会再有文件经过这些链接写入。
+    // 指向传输内容之外的符号链接和原文件没有收到的硬链接不创建，不影响其他文件，
+    // 返回这些没有创建的链接序号
+    async fn create_links(
+        link: &mut TransferLink<'_>,
+        request: &FileTransferRequest,
+        state: &mut ResumeState,
+        entries: &[(u32, PathBuf)],
+    ) -> Vec<u32> {
+        let mut failed = Vec::new();
+        let files: HashMap<&str, usize> = request
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.kind == EntryKind::File)
+            .map(|(index, entry)| (entry.relative_path.as_str(), index))
+            .collect();
+
+        for (index, target) in entries {
+            let entry = &request.files[*index as usize];
+            let link_target = entry.link_target.as_deref().unwrap_or_default();
+            let result = match entry.kind {
+                EntryKind::Symlink => {
+                    match sanitize::validate_symlink_target(&entry.relative_path, link_target) {
+                        Ok(()) => links::create_symlink(link_target, target).await,
+                        Err(e) => {
+                            log::warn!("Refusing symlink {}: {}", entry.relative_path, e);
+                            link.progress.fail_file(*index, "refused");
+                            failed.push(*index);
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    let original = files.get(link_target).map(|&original| {
+                        let received = state.completed.contains(&(original as u32));
+                        (state.targets[original].clone(), received)
+                    });
+                    match original {
+                        // 原文件按重名策略跳过时链接也跳过
+                        Some((None, _)) => {
+                            link.progress.complete_file(*index, "skipped", None);
+                            continue;
+                        }
+                        Some((Some(original), true)) => {
+                            links::create_hardlink(&original, target).await
+                        }
+                        _ => Err(format!("Original file {} was not received", link_target)),
+                    }
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    state.completed.push(*index);
+                    link.progress
+                        .complete_file(*index, "completed", Some(target));
+                }
+                Err(e) => {
+                    log::warn!("Cannot create link {}: {}", entry.relative_path, e);
+                    link.progress.fail_file(*index, "failed");
+                    failed.push(*index);
+                }
+            }
+        }
+        failed
+    }
+
+    async fn next_batch(
+        link: &mut TransferLink<'_>,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<VecDeque<BatchFile>, String> {
+        let frame = link
+            .next_frame(app_handle)
+            .await?
+            .expect(FrameType::Batch)?;
+        let (files, wire_bytes) = batch::unpack(&frame, link.codec)?;
+        let bytes = files.iter().map(|file| file.data.len() as u64).sum();
+        link.progress.advance_compressed(bytes, wire_bytes);
+        link.tuning.record(bytes);
+        Ok(files)
+    }
+
+    // 打包接收的小文件同样先写临时文件再改名，不逐个落盘，
+    // 返回 false 表示校验和不一致，可以请求重传
+    async fn write_batched(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        file_path: &Path,
+        file: BatchFile,
+        state: &mut ResumeState,
+    ) -> Result<bool, String> {
+        if file.index != index || file.data.len() as u64 != entry.size {
+            return Err(format!(
+                "Unexpected batched file {} ({} bytes), expected file {} ({} bytes)",
+                file.index,
+                file.data.len(),
+                index,
+                entry.size
+            ));
+        }
+        if !format!("{:x}", Sha256::digest(&file.data)).eq_ignore_ascii_case(&file.sha256) {
+            log::warn!("Checksum mismatch: {}", entry.relative_path);
+            link.progress.fail_file(index, ERROR_CHECKSUM_MISMATCH);
+            return Ok(false);
+        }
 
-        // 发送初始进度
-        let progress = TransferProgress {
-            file_name: request.file_name.clone(),
-            progress: 0.0,
-            status: "receiving".to_string(),
+        let part_path = partfile::part_path(file_path);
+        let result = async {
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| format!("Failed to create directory: {}", e))?;
+            }
+            // 与单独发送的文件一样落盘后再改名，断电后不会留下内容不完整的文件
+            let part_file = fs::File::create(&part_path)
+                .await
+                .map_err(|e| format!("Failed to create file: {}", e))?;
+            Self::finish_part_file(part_file, file.data, entry).await?;
+            fs::rename(&part_path, file_path)
+                .await
+                .map_err(|e| format!("Failed to move received file into place: {}", e))
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&part_path).await;
+            link.progress.fail_file(index, "failed");
+            return Err(e);
+        }
+
+        // 打包的文件不续传，丢弃之前可能留下的断点
+        state.partial.remove(&index);
+        state.completed.push(index);
+        link.progress.complete_batched(index);
+        Ok(true)
+    }
+
+    // 找出接收方已有相同内容的文件: 保存位置已有的相同文件直接使用，
+    // 内容索引中其他位置的相同文件复制过来，都不再传输
+    async fn find_identical(
+        request: &FileTransferRequest,
+        state: &mut ResumeState,
+        finalizer: &Finalizer<'_>,
+    ) -> HashSet<u32> {
+        let content_index = finalizer.content_index;
+        let mut identical = HashSet::new();
+        for (index, entry) in request.files.iter().enumerate() {
+            let index = index as u32;
+            let Some(sha256) = &entry.sha256 else {
+                continue;
+            };
+            if entry.kind != EntryKind::File || state.completed.contains(&index) {
+                continue;
+            }
+
+            let existing = match sanitize::resolve_received_path(&state.root, &entry.relative_path)
+            {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            match Self::check_existing(&existing, entry, sha256, content_index).await {
+                Ok(true) => {
+                    log::info!("Already have {}", existing.display());
+                    state.targets[index as usize] = Some(existing);
+                    state.completed.push(index);
+                    identical.insert(index);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => log::warn!("Cannot check {}: {}", existing.display(), e),
+            }
+
+            let Some(target) = state.targets[index as usize].clone() else {
+                continue;
+            };
+            let Some(source) = content_index.lock().await.find(sha256, entry.size) else {
+                continue;
+            };
+            if source == target {
+                continue;
+            }
+            match Self::copy_identical(&source, &target, sha256).await {
+                Ok(true) => {
+                    log::info!("Copied {} from {}", target.display(), source.display());
+                    finalizer.finish(entry, &target).await;
+                    state.partial.remove(&index);
+                    state.completed.push(index);
+                    identical.insert(index);
+                }
+                Ok(false) => {
+                    log::warn!("Indexed file {} has changed", source.display());
+                    content_index.lock().await.remove(&source);
+                }
+                Err(e) => log::warn!("Cannot copy {}: {}", source.display(), e),
+            }
+        }
+        identical
+    }
+
+    // 大小一致时才计算校验和，结果记入索引
+    async fn check_existing(
+        path: &Path,
+        entry: &FileEntry,
+        sha256: &str,
+        content_index: &Mutex<ContentIndex>,
+    ) -> Result<bool, String> {
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() && metadata.len() == entry.size => metadata,
+            _ => return Ok(false),
         };
-        let _ = app_handle.emit("transfer-progress", &progress);
+        let existing = dedup::hash_file(path).await?;
+        content_index
+            .lock()
+            .await
+            .record(path, &metadata, &existing);
+        Ok(existing.eq_ignore_ascii_case(sha256))
+    }
 
-        loop {
-            let bytes_read = stream
-                .read(&mut buffer)
+    async fn copy_identical(source: &Path, target: &Path, sha256: &str) -> Result<bool, String> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
                 .await
-                .map_err(|e| format!("Failed to read from stream: {}", e))?;
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+        let part_path = partfile::part_path(target);
+        if !dedup::copy_verified(source, &part_path, sha256).await? {
+            return Ok(false);
+        }
+        if let Err(e) = fs::rename(&part_path, target).await {
+            let _ = fs::remove_file(&part_path).await;
+            return Err(format!("Failed to move copied file into place: {}", e));
+        }
+        Ok(true)
+    }
 
-            if bytes_read == 0 {
-                break; // 连接关闭
+    fn save_resume_state(state: &mut ResumeState) {
+        if let Err(e) = resume::save(state) {
+            log::warn!("Failed to save resume state: {}", e);
+        }
+    }
+
+    // 找出可以续传的临时文件，截掉最后一个断点之后的数据并重新计算前缀校验和
+    async fn prepare_resume(
+        request: &FileTransferRequest,
+        state: &mut ResumeState,
+    ) -> HashMap<u32, PartStart> {
+        let mut starts = HashMap::new();
+        let mut buffer = vec![0; 64 * 1024];
+
+        for (index, entry) in request.files.iter().enumerate() {
+            let index = index as u32;
+            let Some(target) = state.targets[index as usize].clone() else {
+                continue;
+            };
+            if entry.kind != EntryKind::File || state.completed.contains(&index) {
+                continue;
             }
 
-            file.write_all(&buffer[..bytes_read])
+            // 发送方重启后 transfer_id 不同，按文件指纹接管之前的临时文件
+            let partial = state.partial.remove(&index).or_else(|| {
+                resume::find_partial(&state.root, entry).filter(|p| p.target == target)
+            });
+            let Some(partial) = partial.filter(|p| p.offset > 0 && p.offset <= entry.size) else {
+                continue;
+            };
+
+            match Self::verify_part_prefix(&partfile::part_path(&target), &partial, &mut buffer)
                 .await
-                .map_err(|e| format!("Failed to write to file: {}", e))?;
+            {
+                Ok(Some(hasher)) => {
+                    starts.insert(index, PartStart::Resume(partial.offset, hasher));
+                    state.partial.insert(index, partial);
+                }
+                Ok(None) => log::warn!(
+                    "Partial data for {} does not match its checkpoint",
+                    entry.relative_path
+                ),
+                Err(e) => log::warn!("Cannot resume {}: {}", entry.relative_path, e),
+            }
+        }
 
-            bytes_received += bytes_read as u64;
-            let progress_percent = (bytes_received as f64 / request.file_size as f64) * 100.0;
+        starts
+    }
 
-            // 发送进度更新
-            let progress = TransferProgress {
-                file_name: request.file_name.clone(),
-                progress: progress_percent.min(100.0),
-                status: "receiving".to_string(),
+    // 保存位置已有旧版本的大文件按旧版本还原，不续传的文件才考虑。
+    // 只有按重名策略覆盖旧版本时才这样做，改名保存时旧版本与新文件无关
+    async fn find_delta_bases(
+        request: &FileTransferRequest,
+        state: &ResumeState,
+        starts: &mut HashMap<u32, PartStart>,
+    ) {
+        for (index, entry) in request.files.iter().enumerate() {
+            let index = index as u32;
+            let Some(target) = &state.targets[index as usize] else {
+                continue;
+            };
+            if entry.kind != EntryKind::File
+                || entry.size < DELTA_MIN_SIZE
+                || state.completed.contains(&index)
+                || starts.contains_key(&index)
+            {
+                continue;
+            }
+            let Ok(basis) = sanitize::resolve_received_path(&state.root, &entry.relative_path)
+            else {
+                continue;
             };
-            let _ = app_handle.emit("transfer-progress", &progress);
+            if basis != *target {
+                continue;
+            }
+            match fs::metadata(&basis).await {
+                Ok(metadata) if metadata.is_file() && metadata.len() >= DELTA_MIN_SIZE => {
+                    starts.insert(index, PartStart::Delta(basis));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn verify_part_prefix(
+        part_path: &Path,
+        partial: &PartialFile,
+        buffer: &mut [u8],
+    ) -> Result<Option<Sha256>, String> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(part_path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", part_path.display(), e))?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to get file metadata: {}", e))?
+            .len();
+        if len < partial.offset {
+            return Ok(None);
+        }
+        // 断点之后写入的数据没有记录校验和，丢弃后由发送方重发
+        file.set_len(partial.offset)
+            .await
+            .map_err(|e| format!("Failed to truncate {}: {}", part_path.display(), e))?;
+
+        let hasher = Self::hash_prefix(&mut file, partial.offset, buffer).await?;
+        Ok(hasher.filter(|h| format!("{:x}", h.clone().finalize()) == partial.sha256))
+    }
+
+    // 返回 false 表示校验和不一致，临时文件已删除，可以请求重传
+    async fn receive_file(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        file_path: &Path,
+        start: Option<PartStart>,
+        state: &mut ResumeState,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<bool, String> {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+
+        // 先写入临时文件，连接中断时不会留下看似完整的文件
+        let part_path = partfile::part_path(file_path);
+        let result =
+            Self::write_part_file(link, index, entry, &part_path, start, state, app_handle).await;
+        let result = match result {
+            Ok(true) => fs::rename(&part_path, file_path)
+                .await
+                .map(|_| true)
+                .map_err(|e| format!("Failed to move received file into place: {}", e)),
+            other => other,
+        };
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = fs::remove_file(&part_path).await;
+                state.partial.remove(&index);
+                Self::save_resume_state(state);
+                log::warn!("Checksum mismatch: {}", entry.relative_path);
+                link.progress.fail_file(index, ERROR_CHECKSUM_MISMATCH);
+                return Ok(false);
+            }
+            Err(e) => {
+                // 有断点的临时文件保留下来，重连后继续接收
+                if !state.partial.contains_key(&index) {
+                    let _ = fs::remove_file(&part_path).await;
+                }
+                let status = match link.control.state() {
+                    ControlState::Cancelled => "cancelled",
+                    _ => "failed",
+                };
+                link.progress.fail_file(index, status);
+                return Err(e);
+            }
+        }
+
+        state.partial.remove(&index);
+        state.completed.push(index);
+        Self::save_resume_state(state);
+
+        link.progress
+            .complete_file(index, "completed", Some(file_path));
+
+        log::info!("File received: {}", file_path.display());
 
-            if bytes_received >= request.file_size {
+        Ok(true)
+    }
+
+    async fn write_part_file(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        part_path: &Path,
+        start: Option<PartStart>,
+        state: &mut ResumeState,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<bool, String> {
+        let mut writer = match start {
+            Some(PartStart::Delta(basis)) => {
+                return Self::write_delta(link, index, entry, part_path, &basis, app_handle).await;
+            }
+            Some(PartStart::Resume(offset, hasher)) => {
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .open(part_path)
+                    .await
+                    .map_err(|e| format!("Failed to open file: {}", e))?;
+                file.seek(SeekFrom::Start(offset))
+                    .await
+                    .map_err(|e| format!("Failed to seek file: {}", e))?;
+                PartWriter::new(file, offset, hasher)
+            }
+            None => {
+                let file = fs::File::create(part_path)
+                    .await
+                    .map_err(|e| format!("Failed to create file: {}", e))?;
+                PartWriter::new(file, 0, Sha256::new())
+            }
+        };
+
+        link.progress.start_file(index, writer.written, "receiving");
+
+        let received = Self::receive_part_data(
+            link,
+            index,
+            entry,
+            part_path,
+            &mut writer,
+            state,
+            app_handle,
+        )
+        .await;
+        let file_end = match received {
+            Ok(ReceivedData::Stream(file_end)) => file_end,
+            Ok(ReceivedData::Striped { verified }) => {
+                if !verified {
+                    return Ok(false);
+                }
+                return Self::finish_part_file(writer.file, Vec::new(), entry).await;
+            }
+            Err(e) => {
+                // 记录中断前已写入的位置，重连后从这里继续；取消时不需要
+                let cancelled = link.control.state() == ControlState::Cancelled;
+                if writer.written > writer.checkpoint && !cancelled {
+                    writer.save_checkpoint(index, entry, state).await;
+                }
+                return Err(e);
+            }
+        };
+
+        Self::finish_stream(index, entry, writer, file_end).await
+    }
+
+    // 核对结尾帧与写入的数据，数据在传输或写入过程中损坏时由调用方请求重传
+    async fn finish_stream(
+        index: u32,
+        entry: &FileEntry,
+        writer: PartWriter,
+        file_end: FileEndMessage,
+    ) -> Result<bool, String> {
+        if file_end.file_index != index
+            || file_end.size != entry.size
+            || writer.written != entry.size
+        {
+            return Err(format!(
+                "Unexpected end of file {} ({} bytes), expected file {} ({} of {} bytes)",
+                file_end.file_index, file_end.size, index, writer.written, entry.size
+            ));
+        }
+
+        let checksum = format!("{:x}", writer.hasher.finalize());
+        if !checksum.eq_ignore_ascii_case(&file_end.sha256) {
+            return Ok(false);
+        }
+
+        Self::finish_part_file(writer.file, writer.pending, entry).await
+    }
+
+    // 把旧版本的块签名发给发送方，再按收到的指令从旧版本和新数据还原文件，
+    // 还原结果与发送方的整体校验和不一致时由调用方请求完整重传
+    async fn write_delta(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        part_path: &Path,
+        basis_path: &Path,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<bool, String> {
+        let (signature, mut basis) = match Basis::open(index, basis_path).await {
+            Ok((signature, basis)) => (signature, Some(basis)),
+            Err(e) => {
+                log::warn!(
+                    "Cannot use the existing copy of {}: {}",
+                    entry.relative_path,
+                    e
+                );
+                (Signature::empty(index), None)
+            }
+        };
+        link.conn.send(&signature.frame()).await?;
+
+        let file = fs::File::create(part_path)
+            .await
+            .map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = PartWriter::new(file, 0, Sha256::new());
+        link.progress.start_file(index, 0, "receiving");
+
+        let mut reused = 0u64;
+        let file_end = loop {
+            let frame = link.next_frame(app_handle).await?;
+            if frame.frame_type == FrameType::FileEnd {
+                break frame.parse()?;
+            }
+            let frame = frame.expect(FrameType::Delta)?;
+            let (file_index, ops) = delta::parse(&frame)?;
+            if file_index != index {
+                return Err(format!(
+                    "Unexpected delta for file {}, expected file {}",
+                    file_index, index
+                ));
+            }
+
+            let start = writer.written;
+            let reused_before = reused;
+            for op in ops {
+                let copied;
+                let bytes = match op {
+                    DeltaOp::Literal(bytes) => bytes,
+                    DeltaOp::Copy { block, count } => {
+                        let basis = basis.as_mut().ok_or("Unexpected block reference")?;
+                        copied = basis.read(block, count).await?;
+                        reused += copied.len() as u64;
+                        &copied[..]
+                    }
+                };
+                if writer.written + bytes.len() as u64 > entry.size {
+                    return Err("Received more data than announced".to_string());
+                }
+                writer.write(bytes).await?;
+            }
+
+            let wire = frame.payload.len() as u64;
+            link.progress
+                .advance_delta(writer.written - start, reused - reused_before, wire);
+            link.tuning.record(wire);
+            writer.capacity = link.tuning.write_buffer();
+        };
+
+        log::info!(
+            "Rebuilt {} from the existing copy: {} of {} bytes reused",
+            entry.relative_path,
+            reused,
+            entry.size
+        );
+        Self::finish_stream(index, entry, writer, file_end).await
+    }
+
+    // 写入剩余的缓冲数据并落盘
+    async fn finish_part_file(
+        mut file: fs::File,
+        pending: Vec<u8>,
+        entry: &FileEntry,
+    ) -> Result<bool, String> {
+        file.write_all(&pending)
+            .await
+            .map_err(|e| format!("Failed to write to file: {}", e))?;
+        file.flush()
+            .await
+            .map_err(|e| format!("Failed to flush file: {}", e))?;
+        file.sync_all()
+            .await
+            .map_err(|e| format!("Failed to sync file: {}", e))?;
+
+        // 落盘后再核对一次大小
+        let written = file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to get file metadata: {}", e))?
+            .len();
+        if written != entry.size {
+            return Err(format!(
+                "Size mismatch for {}: expected {} bytes, wrote {}",
+                entry.relative_path, entry.size, written
+            ));
+        }
+
+        Ok(true)
+    }
+
+    // 读取一个文件的 Data 帧直到结尾帧，发送方也可能改为并行发送
+    async fn receive_part_data(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        part_path: &Path,
+        writer: &mut PartWriter,
+        state: &mut ResumeState,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<ReceivedData, String> {
+        loop {
+            let frame = link.next_frame(app_handle).await?;
+            if frame.frame_type == FrameType::FileEnd {
+                let file_end: FileEndMessage = frame.parse()?;
+                if file_end.file_index == index && file_end.size == entry.size {
+                    Self::receive_hole(link, writer, entry.size).await?;
+                }
+                return Ok(ReceivedData::Stream(file_end));
+            }
+            if frame.frame_type == FrameType::Stripe {
+                let stripe: StripeMessage = frame.parse()?;
+                let verified = Self::receive_striped(
+                    link, index, entry, part_path, stripe, writer, app_handle,
+                )
+                .await?;
+                return Ok(ReceivedData::Striped { verified });
+            }
+            let (frame, wire) = frame.decompress(link.codec)?;
+            let frame = frame.expect(FrameType::Data)?;
+            let chunk = frame.parse_data()?;
+
+            // 发送方校验前缀不一致时会从头发送
+            if chunk.file_index == index && chunk.offset == 0 && writer.written > 0 {
+                log::warn!(
+                    "Sender restarted {} from the beginning",
+                    entry.relative_path
+                );
+                writer.restart().await?;
+                link.progress.restart_file();
+            }
+            if chunk.file_index == index && chunk.offset <= entry.size {
+                Self::receive_hole(link, writer, chunk.offset).await?;
+            }
+
+            if chunk.file_index != index || chunk.offset != writer.written {
+                return Err(format!(
+                    "Unexpected data for file {} at offset {}, expected file {} at offset {}",
+                    chunk.file_index, chunk.offset, index, writer.written
+                ));
+            }
+
+            if writer.written + chunk.bytes.len() as u64 > entry.size {
+                return Err("Received more data than announced".to_string());
+            }
+
+            writer.write(chunk.bytes).await?;
+            link.progress
+                .advance_compressed(chunk.bytes.len() as u64, wire as u64);
+            link.tuning.record(chunk.bytes.len() as u64);
+            writer.capacity = link.tuning.write_buffer();
+            if writer.written - writer.checkpoint >= CHECKPOINT_INTERVAL {
+                writer.save_checkpoint(index, entry, state).await;
+            }
+        }
+    }
+
+    // 发送方跳过了稀疏文件在 offset 之前的空洞
+    async fn receive_hole(
+        link: &mut TransferLink<'_>,
+        writer: &mut PartWriter,
+        offset: u64,
+    ) -> Result<(), String> {
+        if !link.sparse || offset <= writer.written {
+            return Ok(());
+        }
+        let len = offset - writer.written;
+        writer.skip_hole(len).await?;
+        link.progress.advance_hole(len);
+        Ok(())
+    }
+
+    // 从并行数据连接接收 writer.written 之后的部分，返回各区间的校验和是否一致
+    async fn receive_striped(
+        link: &mut TransferLink<'_>,
+        index: u32,
+        entry: &FileEntry,
+        part_path: &Path,
+        stripe: StripeMessage,
+        writer: &mut PartWriter,
+        app_handle: &tauri::AppHandle,
+    ) -> Result<bool, String> {
+        let ranges = stripe.ranges;
+        let contiguous = ranges
+            .windows(2)
+            .all(|pair| pair[0].offset + pair[0].len == pair[1].offset);
+        let start = ranges.first().map(|range| range.offset);
+        let end = ranges.last().map(|range| range.offset + range.len);
+        if stripe.file_index != index
+            || ranges.is_empty()
+            || ranges.len() > link.stripes.limit()
+            || !contiguous
+            || end != Some(entry.size)
+            || start.is_some_and(|start| start != 0 && start != writer.written)
+        {
+            return Err(format!(
+                "Unexpected parallel ranges for file {}",
+                stripe.file_index
+            ));
+        }
+
+        // 发送方校验前缀不一致时会从头发送
+        if start == Some(0) && writer.written > 0 {
+            log::warn!(
+                "Sender restarted {} from the beginning",
+                entry.relative_path
+            );
+            writer.restart().await?;
+            link.progress.restart_file();
+        }
+
+        // 预留整个文件的空间，各区间直接写到临时文件的对应位置
+        writer.flush().await?;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(part_path)
+            .await
+            .map_err(|e| format!("Failed to open file: {}", e))?
+            .into_std()
+            .await;
+        let size = entry.size;
+        let file = tokio::task::spawn_blocking(move || {
+            partfile::preallocate(&file, size).map(|()| Arc::new(file))
+        })
+        .await
+        .map_err(|e| format!("Failed to allocate file: {}", e))?
+        .map_err(|e| format!("Failed to allocate file: {}", e))?;
+
+        let conns = link.stripes.take(ranges.len()).await?;
+        let counters: Vec<_> = ranges
+            .iter()
+            .map(|_| Arc::new(RangeProgress::default()))
+            .collect();
+        let mut workers = JoinSet::new();
+        for (i, (conn, range)) in conns.into_iter().zip(ranges.iter().copied()).enumerate() {
+            let task = stripe::receive_range(
+                conn,
+                index,
+                file.clone(),
+                range,
+                link.codec,
+                !stripe.whole_file,
+                counters[i].clone(),
+            );
+            workers.spawn(async move { (i, task.await) });
+        }
+
+        let (results, pending) = match link.run_stripes(workers, &counters, app_handle).await {
+            Ok(finished) => finished,
+            Err(e) => {
+                // 第一个区间连续写入的部分可以作为断点
+                if link.control.state() != ControlState::Cancelled {
+                    Self::rewind_to_prefix(writer, &ranges, &counters, part_path).await;
+                }
+                return Err(e);
+            }
+        };
+
+        let frame = match pending {
+            Some(frame) => frame,
+            None => link.next_frame(app_handle).await?,
+        };
+        let file_end: FileEndMessage = frame.expect(FrameType::FileEnd)?.parse()?;
+        let (conns, checksums): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+        link.stripes.restore(conns);
+
+        let expected = if stripe.whole_file {
+            0
+        } else {
+            checksums.len()
+        };
+        if file_end.file_index != index
+            || file_end.size != entry.size
+            || file_end.ranges.len() != expected
+        {
+            return Err(format!(
+                "Unexpected end of file {} ({} bytes), expected file {} ({} bytes)",
+                file_end.file_index, file_end.size, index, entry.size
+            ));
+        }
+
+        writer.written = entry.size;
+        if stripe.whole_file {
+            let checksum = dedup::hash_file(part_path).await?;
+            return Ok(checksum.eq_ignore_ascii_case(&file_end.sha256));
+        }
+        Ok(checksums
+            .iter()
+            .zip(&file_end.ranges)
+            .all(|(local, remote)| local.eq_ignore_ascii_case(remote)))
+    }
+
+    // 并行接收中断后，把 writer 退回到从文件开头连续写入的位置并重新计算校验状态
+    async fn rewind_to_prefix(
+        writer: &mut PartWriter,
+        ranges: &[ByteRange],
+        counters: &[Arc<RangeProgress>],
+        part_path: &Path,
+    ) {
+        let mut prefix = writer.written;
+        for (range, counter) in ranges.iter().zip(counters) {
+            let written = counter.done.load(Ordering::Relaxed);
+            prefix = range.offset + written;
+            if written < range.len {
                 break;
             }
         }
+        if prefix <= writer.written {
+            return;
+        }
 
-        // 传输完成
-        let progress = TransferProgress {
-            file_name: request.file_name.clone(),
-            progress: 100.0,
-            status: "completed".to_string(),
+        let hasher = match fs::File::open(part_path).await {
+            Ok(mut file) => {
+                let mut buffer = vec![0; 1024 * 1024];
+                Self::hash_prefix(&mut file, prefix, &mut buffer).await
+            }
+            Err(e) => Err(format!("Failed to open file: {}", e)),
         };
-        let _ = app_handle.emit("transfer-progress", &progress);
+        match hasher {
+            Ok(Some(hasher)) => {
+                writer.written = prefix;
+                writer.hasher = hasher;
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+}
 
-        log::info!("File received: {}", file_path.display());
+// 一个文件按顺序接收时的结尾帧，或并行接收的校验结果
+enum ReceivedData {
+    Stream(FileEndMessage),
+    Striped { verified: bool },
+}
+
+// 一次传输的连接和控制通道
+struct TransferLink<'a> {
+    conn: &'a mut Connection,
+    control: &'a mut TransferControl,
+    progress: &'a mut ProgressTracker,
+    // 大文件拆分发送使用的并行数据连接
+    stripes: Stripes,
+    tuning: LinkTuning,
+    // 对端支持 Control 帧时才通知对端
+    remote: bool,
+    // 明文会话中不压缩的数据块由内核从页缓存发送，不复制到帧缓冲区
+    sendfile: bool,
+    // 协商的压缩算法，每个数据块是否压缩由发送方决定
+    codec: Option<Codec>,
+    // 对端支持时小文件打包发送
+    batching: bool,
+    // 对端支持时稀疏文件跳过空洞发送
+    sparse: bool,
+}
 
+impl<'a> TransferLink<'a> {
+    fn new(
+        conn: &'a mut Connection,
+        control: &'a mut TransferControl,
+        progress: &'a mut ProgressTracker,
+        stripes: Stripes,
+        mut tuning: LinkTuning,
+        session: &SessionParams,
+    ) -> Self {
+        let remote = session.features.iter().any(|f| f == FEATURE_CONTROL);
+        let sendfile = sendfile::supported(session);
+        tuning.describe(stripes.limit(), sendfile);
+        Self {
+            conn,
+            control,
+            progress,
+            stripes,
+            tuning,
+            remote,
+            sendfile,
+            codec: Codec::from_session(session),
+            batching: session.features.iter().any(|f| f == FEATURE_BATCH),
+            sparse: session.features.iter().any(|f| f == FEATURE_SPARSE),
+        }
+    }
+
+    // 双方按同样的规则决定哪些文件打包发送，打包的文件不续传
+    fn batched(&self, entry: &FileEntry) -> bool {
+        self.batching && entry.size <= BATCH_FILE_SIZE
+    }
+
+    // 等待所有并行任务结束，同时处理控制指令并汇总进度，
+    // 期间控制连接上收到的非控制帧原样返回
+    async fn run_stripes(
+        &mut self,
+        workers: JoinSet<(usize, StripeResult)>,
+        counters: &[Arc<RangeProgress>],
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(Vec<(Connection, String)>, Option<Frame>), String> {
+        // 拆分期间的吞吐量单独统计，用于比较是否比单条连接快
+        self.tuning.set_striped(true);
+        let result = self.poll_stripes(workers, counters, app_handle).await;
+        self.tuning.set_striped(false);
+        result
+    }
+
+    async fn poll_stripes(
+        &mut self,
+        mut workers: JoinSet<(usize, StripeResult)>,
+        counters: &[Arc<RangeProgress>],
+        app_handle: &tauri::AppHandle,
+    ) -> Result<(Vec<(Connection, String)>, Option<Frame>), String> {
+        let mut results: Vec<Option<(Connection, String)>> =
+            (0..counters.len()).map(|_| None).collect();
+        let mut pending = None;
+        let mut reported = 0u64;
+        let mut reported_wire = 0u64;
+        let mut ticker = time::interval(Duration::from_millis(100));
+
+        while !workers.is_empty() {
+            self.announce(app_handle).await?;
+            tokio::select! {
+                joined = workers.join_next() => {
+                    if let Some(joined) = joined {
+                        let (i, result) =
+                            joined.map_err(|e| format!("Parallel transfer task failed: {}", e))?;
+                        match result {
+                            Ok(finished) => results[i] = Some(finished),
+                            Err(e) => return Err(self.stripe_failure(e, pending, app_handle).await),
+                        }
+                    }
+                }
+                frame = self.conn.recv(), if pending.is_none() => {
+                    let frame = frame?;
+                    if frame.frame_type == FrameType::Control {
+                        self.apply_remote(&frame, app_handle)?;
+                    } else {
+                        pending = Some(frame);
+                    }
+                }
+                _ = self.control.changed() => {}
+                _ = ticker.tick() => {}
+            }
+
+            let total: u64 = counters
+                .iter()
+                .map(|c| c.done.load(Ordering::Relaxed))
+                .sum();
+            let wire: u64 = counters
+                .iter()
+                .map(|c| c.wire.load(Ordering::Relaxed))
+                .sum();
+            self.progress
+                .advance_compressed(total - reported, wire - reported_wire);
+            self.tuning.record(total - reported);
+            reported = total;
+            reported_wire = wire;
+        }
+
+        Ok((results.into_iter().flatten().collect(), pending))
+    }
+
+    // 数据连接出错通常是因为一方取消或出错，先通知对端，再看对端是否说明了原因
+    async fn stripe_failure(
+        &mut self,
+        error: String,
+        pending: Option<Frame>,
+        app_handle: &tauri::AppHandle,
+    ) -> String {
+        if let Err(e) = self.announce(app_handle).await {
+            return e;
+        }
+        let frame = match pending {
+            Some(frame) => Ok(frame),
+            None => match time::timeout(STRIPE_FAILURE_GRACE, self.conn.recv()).await {
+                Ok(frame) => frame,
+                Err(_) => return error,
+            },
+        };
+        let result = frame.and_then(|frame| {
+            let frame = frame.expect(FrameType::Control)?;
+            self.apply_remote(&frame, app_handle)
+        });
+        match result {
+            Err(e) => e,
+            Ok(()) => error,
+        }
+    }
+
+    // 读取对端的下一个帧，等待期间处理双方的控制指令
+    async fn next_frame(&mut self, app_handle: &tauri::AppHandle) -> Result<Frame, String> {
+        loop {
+            self.announce(app_handle).await?;
+            tokio::select! {
+                frame = self.conn.recv() => {
+                    let frame = frame?;
+                    if frame.frame_type != FrameType::Control {
+                        return Ok(frame);
+                    }
+                    self.apply_remote(&frame, app_handle)?;
+                }
+                _ = self.control.changed() => {}
+            }
+        }
+    }
+
+    // 发送方在每个数据块之前调用，暂停时在这里等待继续或取消
+    async fn wait_if_paused(&mut self, app_handle: &tauri::AppHandle) -> Result<(), String> {
+        loop {
+            // 发送数据期间对端只会发来 Control 帧或错误
+            while let Some(frame) = self.conn.try_recv() {
+                let frame = frame?.expect(FrameType::Control)?;
+                self.apply_remote(&frame, app_handle)?;
+            }
+            self.announce(app_handle).await?;
+
+            if self.control.state() == ControlState::Running {
+                return Ok(());
+            }
+            tokio::select! {
+                frame = self.conn.recv() => {
+                    let frame = frame?.expect(FrameType::Control)?;
+                    self.apply_remote(&frame, app_handle)?;
+                }
+                _ = self.control.changed() => {}
+            }
+        }
+    }
+
+    // 把本地的状态变化通知对端和界面，已取消时结束传输
+    async fn announce(&mut self, app_handle: &tauri::AppHandle) -> Result<(), String> {
+        if let Some(state) = self.control.take_change() {
+            log::info!("Transfer {} {:?}", self.control.transfer_id, state);
+            if self.remote {
+                let message = ControlMessage {
+                    action: state.action(),
+                };
+                self.conn
+                    .send(&Frame::json(FrameType::Control, &message)?)
+                    .await?;
+            }
+            self.emit_state(state, app_handle);
+        }
+
+        if self.control.state() == ControlState::Cancelled {
+            return Err(TRANSFER_CANCELLED.to_string());
+        }
+        Ok(())
+    }
+
+    fn apply_remote(&mut self, frame: &Frame, app_handle: &tauri::AppHandle) -> Result<(), String> {
+        let message: ControlMessage = frame.parse()?;
+        let state = self.control.apply_remote(message.action);
+        log::info!("Peer set transfer {} {:?}", self.control.transfer_id, state);
+        self.emit_state(state, app_handle);
+
+        if state == ControlState::Cancelled {
+            return Err("Transfer cancelled by peer".to_string());
+        }
         Ok(())
     }
+
+    fn emit_state(&self, state: ControlState, app_handle: &tauri::AppHandle) {
+        let changed = TransferStateChanged {
+            transfer_id: self.control.transfer_id.clone(),
+            state,
+        };
+        let _ = app_handle.emit("transfer-state", &changed);
+    }
+}
+
+// 接收的文件落盘后按设置恢复文件属性，再记入内容索引
+struct Finalizer<'a> {
+    content_index: &'a Mutex<ContentIndex>,
+    metadata: bool,
+    xattrs: bool,
+}
+
+impl Finalizer<'_> {
+    // 属性恢复失败不影响传输结果；清单中的校验和可能与实际内容不符，使用索引时会重新校验
+    async fn finish(&self, entry: &FileEntry, path: &Path) {
+        if self.metadata {
+            if let Err(e) = attributes::apply(path, entry, self.xattrs) {
+                log::warn!("Cannot restore attributes of {}: {}", path.display(), e);
+            }
+        }
+        let Some(sha256) = &entry.sha256 else {
+            return;
+        };
+        if let Ok(metadata) = fs::metadata(path).await {
+            self.content_index
+                .lock()
+                .await
+                .record_received(path, &metadata, sha256);
+        }
+    }
+}
+
+// 正在写入的临时文件，以及已写入前缀的校验状态
+struct PartWriter {
+    file: fs::File,
+    // 已计入 written 但尚未写入文件的数据，攒够 capacity 再写入
+    pending: Vec<u8>,
+    capacity: usize,
+    written: u64,
+    hasher: Sha256,
+    // 最近一次记录断点时的写入位置
+    checkpoint: u64,
+}
+
+impl PartWriter {
+    fn new(file: fs::File, written: u64, hasher: Sha256) -> Self {
+        Self {
+            file,
+            pending: Vec::new(),
+            capacity: 0,
+            written,
+            hasher,
+            checkpoint: written,
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        if self.pending.is_empty() && bytes.len() >= self.capacity {
+            return self.write_file(bytes).await;
+        }
+
+        self.pending.extend_from_slice(bytes);
+        if self.pending.len() >= self.capacity {
+            self.write_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_file(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.file
+            .write_all(bytes)
+            .await
+            .map_err(|e| format!("Failed to write to file: {}", e))
+    }
+
+    async fn write_pending(&mut self) -> Result<(), String> {
+        let mut pending = std::mem::take(&mut self.pending);
+        let result = self.write_file(&pending).await;
+        pending.clear();
+        self.pending = pending;
+        result
+    }
+
+    // 把缓冲的数据写入文件
+    async fn flush(&mut self) -> Result<(), String> {
+        if !self.pending.is_empty() {
+            self.write_pending().await?;
+        }
+        self.file
+            .flush()
+            .await
+            .map_err(|e| format!("Failed to flush file: {}", e))
+    }
+
+    // 空洞不写入数据，截掉之后可能残留的旧内容再扩展文件长度，
+    // 文件系统支持时不占用磁盘空间
+    async fn skip_hole(&mut self, len: u64) -> Result<(), String> {
+        self.flush().await?;
+        sparse::hash_zeros(&mut self.hasher, len);
+        for size in [self.written, self.written + len] {
+            self.file
+                .set_len(size)
+                .await
+                .map_err(|e| format!("Failed to extend file: {}", e))?;
+        }
+        self.written += len;
+        self.file
+            .seek(SeekFrom::Start(self.written))
+            .await
+            .map_err(|e| format!("Failed to seek file: {}", e))?;
+        Ok(())
+    }
+
+    async fn restart(&mut self) -> Result<(), String> {
+        self.pending.clear();
+        self.file
+            .set_len(0)
+            .await
+            .map_err(|e| format!("Failed to truncate file: {}", e))?;
+        self.file
+            .seek(SeekFrom::Start(0))
+            .await
+            .map_err(|e| format!("Failed to seek file: {}", e))?;
+        self.hasher = Sha256::new();
+        self.written = 0;
+        self.checkpoint = 0;
+        Ok(())
+    }
+
+    // 数据落盘后才记录断点，保证记录的前缀一定完整
+    async fn save_checkpoint(&mut self, index: u32, entry: &FileEntry, state: &mut ResumeState) {
+        let synced = match self.flush().await {
+            Ok(()) => self.file.sync_data().await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = synced {
+            log::warn!("Failed to sync {}: {}", entry.relative_path, e);
+            return;
+        }
+        let Some(target) = state.targets[index as usize].clone() else {
+            return;
+        };
+
+        state.partial.insert(
+            index,
+            PartialFile {
+                relative_path: entry.relative_path.clone(),
+                size: entry.size,
+                modified: entry.modified,
+                target,
+                offset: self.written,
+                sha256: format!("{:x}", self.hasher.clone().finalize()),
+            },
+        );
+        self.checkpoint = self.written;
+        FileTransferManager::save_resume_state(state);
+    }
 }
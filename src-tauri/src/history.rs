@@ -0,0 +1,67 @@
+use crate::device::Device;
+use crate::settings;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+const HISTORY_FILE: &str = "history.json";
+// 只保留最近的记录，避免文件无限增长
+const MAX_RECORDS: usize = 500;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferRecord {
+    pub transfer_id: String,
+    // "send" 或 "receive"
+    pub direction: String,
+    pub peer: Device,
+    // 接收时对端身份是否与已发现的设备和连接地址一致
+    pub verified: bool,
+    pub file_count: usize,
+    pub total_size: u64,
+    // "completed"、"declined"、"failed"、"checksum_mismatch" 或 "cancelled"
+    pub status: String,
+    pub message: String,
+    pub timestamp: DateTime<Local>,
+}
+
+pub struct TransferHistory {
+    records: Vec<TransferRecord>,
+}
+
+impl TransferHistory {
+    pub fn load() -> Self {
+        let records = settings::app_data_dir()
+            .ok()
+            .and_then(|dir| std::fs::read_to_string(dir.join(HISTORY_FILE)).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self { records }
+    }
+
+    pub fn records(&self) -> &[TransferRecord] {
+        &self.records
+    }
+
+    pub fn add(&mut self, record: TransferRecord) {
+        self.records.push(record);
+        if self.records.len() > MAX_RECORDS {
+            let excess = self.records.len() - MAX_RECORDS;
+            self.records.drain(..excess);
+        }
+
+        if let Err(e) = self.save() {
+            log::warn!("Failed to save transfer history: {}", e);
+        }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let dir = settings::app_data_dir()?;
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+        let content = serde_json::to_string(&self.records)
+            .map_err(|e| format!("Failed to serialize history: {}", e))?;
+        std::fs::write(dir.join(HISTORY_FILE), content)
+            .map_err(|e| format!("Failed to write history: {}", e))
+    }
+}
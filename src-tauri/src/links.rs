@@ -0,0 +1,78 @@
+use std::fs::Metadata;
+use std::path::Path;
+use tokio::fs;
+
+// 有多个名字的文件返回设备号和 inode 号，清单据此找出同一批次中的硬链接
+pub fn inode_key(metadata: &Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+// 读取符号链接本身的内容，统一使用 / 分隔
+pub async fn read_symlink(path: &Path) -> Result<String, String> {
+    let target = fs::read_link(path)
+        .await
+        .map_err(|e| format!("Failed to read symlink {}: {}", path.display(), e))?;
+    let target = target
+        .to_str()
+        .ok_or_else(|| format!("Non UTF-8 symlink target: {}", path.display()))?;
+    if cfg!(windows) {
+        Ok(target.replace('\\', "/"))
+    } else {
+        Ok(target.to_string())
+    }
+}
+
+// 重名策略已经决定可以替换同名的文件或链接，目录不替换，之后的创建会失败
+async fn remove_existing(path: &Path) -> Result<(), String> {
+    match fs::symlink_metadata(path).await {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path)
+            .await
+            .map_err(|e| format!("Failed to replace {}: {}", path.display(), e)),
+        _ => Ok(()),
+    }
+}
+
+// 按清单创建符号链接，target 已经校验过只指向本次接收的文件夹之内
+pub async fn create_symlink(target: &str, path: &Path) -> Result<(), String> {
+    remove_existing(path).await?;
+    #[cfg(unix)]
+    let result = fs::symlink(target, path).await;
+    #[cfg(windows)]
+    let result = {
+        // Windows 区分文件链接和目录链接，链接最后创建，此时可以按目标的类型选择
+        let target = target.replace('/', "\\");
+        let resolved = path.parent().unwrap_or(Path::new("")).join(&target);
+        if fs::metadata(&resolved).await.is_ok_and(|m| m.is_dir()) {
+            fs::symlink_dir(&target, path).await
+        } else {
+            fs::symlink_file(&target, path).await
+        }
+    };
+    result.map_err(|e| format!("Failed to create symlink {}: {}", path.display(), e))
+}
+
+// 为已经收到的文件再建一个名字，文件系统不支持硬链接时改为复制
+pub async fn create_hardlink(original: &Path, path: &Path) -> Result<(), String> {
+    remove_existing(path).await?;
+    if let Err(e) = fs::hard_link(original, path).await {
+        log::warn!(
+            "Cannot hardlink {} to {} ({}), copying instead",
+            path.display(),
+            original.display(),
+            e
+        );
+        fs::copy(original, path)
+            .await
+            .map_err(|e| format!("Failed to copy {}: {}", original.display(), e))?;
+    }
+    Ok(())
+}
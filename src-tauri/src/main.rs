@@ -1,15 +1,46 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod attributes;
+mod batch;
+mod collision;
+mod compression;
+mod control;
+mod dedup;
+mod delta;
 mod device;
 mod file_transfer;
+mod history;
+mod links;
+mod manifest;
 mod network;
+mod partfile;
+mod progress;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod resume;
+mod sanitize;
+mod scheduler;
+mod sendfile;
+mod settings;
+mod sparse;
+mod stripe;
+mod tuning;
 // mod crypto;
 // mod tray;
 
+use collision::CollisionPolicy;
 use device::{Device, DeviceManager};
-use file_transfer::FileTransferManager;
+use file_transfer::{FileTransferManager, PendingDecisions};
+use history::TransferRecord;
 use network::NetworkManager;
+use protocol::ControlAction;
+use queue::TransferPriority;
+use ratelimit::BandwidthScope;
+use scheduler::{ScheduledTransfer, TransferScheduler};
+use settings::TransferSettings;
+use tuning::LinkDiagnostics;
 // use tray::{create_system_tray, show_tray_notification};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -25,8 +56,13 @@ struct TransferProgress {
 
 struct AppState {
     device_manager: Arc<Mutex<DeviceManager>>,
-    transfer_manager: Arc<Mutex<FileTransferManager>>,
+    // 各方法只短暂锁住内部状态，不需要再整体加锁
+    transfer_manager: Arc<FileTransferManager>,
+    // 答复传输请求只用到这张表，不需要等待正在进行的传输
+    pending_decisions: PendingDecisions,
     network_manager: Arc<Mutex<NetworkManager>>,
+    settings: Arc<Mutex<TransferSettings>>,
+    transfer_scheduler: TransferScheduler,
 }
 
 #[tauri::command]
@@ -45,10 +81,10 @@ async fn start_device_scan(
 
     tokio::spawn(async move {
         let mut nm = network_manager.lock().await;
-        let dm = device_manager.lock().await;
+        let current_device = device_manager.lock().await.get_current_device().clone();
 
         if let Err(e) = nm
-            .start_discovery(&app_handle, dm.get_current_device())
+            .start_discovery(&app_handle, &current_device, device_manager.clone())
             .await
         {
             log::error!("Failed to start device discovery: {}", e);
@@ -62,10 +98,10 @@ async fn start_device_scan(
 async fn send_files(
     target_device_id: String,
     file_paths: Vec<String>,
+    priority: Option<TransferPriority>,
     state: State<'_, AppState>,
     app_handle: tauri::AppHandle,
-) -> Result<(), String> {
-    let transfer_manager = state.transfer_manager.clone();
+) -> Result<String, String> {
     let device_manager = state.device_manager.lock().await;
 
     // 查找目标设备
@@ -78,13 +114,153 @@ async fn send_files(
 
     drop(device_manager);
 
-    tokio::spawn(async move {
-        let mut tm = transfer_manager.lock().await;
-        if let Err(e) = tm.send_files(target_device, file_paths, app_handle).await {
-            log::error!("Failed to send files: {}", e);
+    // 加入发送队列，名额空出后由调度器启动
+    Ok(state
+        .transfer_scheduler
+        .enqueue(
+            target_device,
+            file_paths,
+            priority.unwrap_or_default(),
+            app_handle,
+        )
+        .await)
+}
+
+#[tauri::command]
+async fn list_transfers(state: State<'_, AppState>) -> Result<Vec<ScheduledTransfer>, String> {
+    Ok(state.transfer_scheduler.list().await)
+}
+
+#[tauri::command]
+async fn list_queue(state: State<'_, AppState>) -> Result<Vec<ScheduledTransfer>, String> {
+    Ok(state.transfer_scheduler.list_queue())
+}
+
+// position 为空时按优先级重新排列
+#[tauri::command]
+async fn reorder_queue(
+    transfer_id: String,
+    position: Option<usize>,
+    priority: Option<TransferPriority>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .transfer_scheduler
+        .reorder(&transfer_id, position, priority)
+}
+
+#[tauri::command]
+async fn remove_from_queue(transfer_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state.transfer_scheduler.remove(&transfer_id).await
+}
+
+#[tauri::command]
+async fn respond_to_transfer(
+    transfer_id: String,
+    accept: bool,
+    save_dir: Option<String>,
+    collision_policy: Option<CollisionPolicy>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .pending_decisions
+        .respond(&transfer_id, accept, save_dir, collision_policy)
+        .await
+}
+
+#[tauri::command]
+async fn pause_transfer(transfer_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state
+        .transfer_scheduler
+        .control(&transfer_id, ControlAction::Pause)
+        .await
+}
+
+#[tauri::command]
+async fn resume_transfer(transfer_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state
+        .transfer_scheduler
+        .control(&transfer_id, ControlAction::Resume)
+        .await
+}
+
+#[tauri::command]
+async fn cancel_transfer(transfer_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state
+        .transfer_scheduler
+        .control(&transfer_id, ControlAction::Cancel)
+        .await
+}
+
+#[tauri::command]
+async fn get_transfer_history(state: State<'_, AppState>) -> Result<Vec<TransferRecord>, String> {
+    Ok(state.transfer_manager.get_history().await)
+}
+
+// 正在进行的传输的链路参数，排查传输慢的原因
+#[tauri::command]
+async fn get_transfer_diagnostics(
+    state: State<'_, AppState>,
+) -> Result<Vec<LinkDiagnostics>, String> {
+    Ok(state.transfer_manager.diagnostics())
+}
+
+#[tauri::command]
+async fn get_transfer_settings(state: State<'_, AppState>) -> Result<TransferSettings, String> {
+    Ok(state.settings.lock().await.clone())
+}
+
+#[tauri::command]
+async fn update_transfer_settings(
+    settings: TransferSettings,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    settings.save()?;
+    state.transfer_manager.limiter().configure(&settings);
+    *state.settings.lock().await = settings;
+    Ok(())
+}
+
+// 调整发送限速，字节/秒，0 表示不限速，正在进行的传输立即生效。
+// 总限速和设备限速保存到设置中，单个传输的限速在传输结束后失效
+#[tauri::command]
+async fn set_bandwidth_limit(
+    scope: BandwidthScope,
+    bytes_per_sec: u64,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    // 只能为已发现的设备设置限速，设置中不会留下不存在的设备
+    if let BandwidthScope::Device { device_id } = &scope {
+        let device_manager = state.device_manager.lock().await;
+        if bytes_per_sec > 0
+            && !device_manager
+                .get_devices()
+                .iter()
+                .any(|d| &d.id == device_id)
+        {
+            return Err("Target device not found".to_string());
         }
-    });
+    }
 
+    let limiter = state.transfer_manager.limiter();
+    let mut settings = state.settings.lock().await;
+    match scope {
+        BandwidthScope::Global => settings.bandwidth_limit = bytes_per_sec,
+        BandwidthScope::Device { device_id } if bytes_per_sec == 0 => {
+            settings.device_bandwidth_limits.remove(&device_id);
+        }
+        BandwidthScope::Device { device_id } => {
+            settings
+                .device_bandwidth_limits
+                .insert(device_id, bytes_per_sec);
+        }
+        BandwidthScope::Transfer { transfer_id } => {
+            limiter.set_transfer_limit(&transfer_id, bytes_per_sec);
+            return Ok(());
+        }
+    }
+    settings.save()?;
+    limiter.configure(&settings);
     Ok(())
 }
 
@@ -93,13 +269,24 @@ async fn main() {
     env_logger::init();
 
     let device_manager = Arc::new(Mutex::new(DeviceManager::new().await));
-    let transfer_manager = Arc::new(Mutex::new(FileTransferManager::new()));
+    let settings = Arc::new(Mutex::new(TransferSettings::load()));
+    let transfer_manager =
+        Arc::new(FileTransferManager::new(settings.clone(), device_manager.clone()).await);
+    let transfer_scheduler = TransferScheduler::new(
+        transfer_manager.clone(),
+        settings.clone(),
+        device_manager.clone(),
+    );
     let network_manager = Arc::new(Mutex::new(NetworkManager::new()));
 
+    let scheduler = transfer_scheduler.clone();
     let app_state = AppState {
         device_manager,
         transfer_manager: transfer_manager.clone(),
+        pending_decisions: transfer_manager.decisions(),
         network_manager,
+        settings,
+        transfer_scheduler,
     };
 
     tauri::Builder::default()
@@ -113,7 +300,20 @@ async fn main() {
         .invoke_handler(tauri::generate_handler![
             get_device_info,
             start_device_scan,
-            send_files
+            send_files,
+            list_transfers,
+            list_queue,
+            reorder_queue,
+            remove_from_queue,
+            respond_to_transfer,
+            pause_transfer,
+            resume_transfer,
+            cancel_transfer,
+            get_transfer_history,
+            get_transfer_diagnostics,
+            get_transfer_settings,
+            update_transfer_settings,
+            set_bandwidth_limit
         ])
                 // .on_window_event(|window, event| {
         //     if let tauri::WindowEvent::CloseRequested { api, .. } = event {
@@ -138,10 +338,15 @@ async fn main() {
             let handle = app.handle().clone();
             let tm = transfer_manager.clone();
             tokio::spawn(async move {
-                let ftm = tm.lock().await;
-                if let Err(e) = ftm.start_file_server(handle).await {
+                // 先清理上次运行遗留的临时文件，再开始接收新的传输
+                let _ = tokio::task::spawn_blocking(partfile::cleanup_stale_part_files).await;
+
+                if let Err(e) = tm.start_file_server(handle.clone()).await {
                     log::error!("Failed to start file server: {}", e);
                 }
+
+                // 上次退出时未完成的发送任务重新排队
+                scheduler.restore(handle).await;
             });
             Ok(())
         })
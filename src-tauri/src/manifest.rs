@@ -0,0 +1,283 @@
+use crate::attributes;
+use crate::links;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Directory,
+    // 符号链接，link_target 为链接内容
+    Symlink,
+    // 与清单中较早的文件是同一个文件，link_target 为那个文件的相对路径
+    Hardlink,
+}
+
+// 清单中的一项，relative_path 始终使用 / 分隔
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    pub relative_path: String,
+    pub size: u64,
+    #[serde(default)]
+    pub kind: EntryKind,
+    // 修改时间，毫秒级 Unix 时间戳
+    #[serde(default)]
+    pub modified: Option<i64>,
+    // 文件内容的 SHA-256，接收方据此找出已有的相同文件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // Unix 权限位，接收方据此恢复可执行位等
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    // user 命名空间的扩展属性，值为 base64，只在发送方开启 preserve_xattrs 时附带
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+}
+
+// 发送方的清单，sources 与 entries 一一对应
+pub struct Manifest {
+    pub entries: Vec<FileEntry>,
+    pub sources: Vec<PathBuf>,
+    // 有多个名字的文件第一次出现时的相对路径
+    inodes: HashMap<(u64, u64), String>,
+}
+
+impl Manifest {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn has_links(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|e| matches!(e.kind, EntryKind::Symlink | EntryKind::Hardlink))
+    }
+
+    // 同一批次中再次出现的文件记为硬链接，不再重复发送内容
+    fn push(&mut self, relative_path: String, metadata: &Metadata, source: PathBuf) {
+        let (size, kind, link_target) = if metadata.is_dir() {
+            (0, EntryKind::Directory, None)
+        } else if let Some(key) = links::inode_key(metadata) {
+            match self.inodes.get(&key) {
+                Some(original) => (0, EntryKind::Hardlink, Some(original.clone())),
+                None => {
+                    self.inodes.insert(key, relative_path.clone());
+                    (metadata.len(), EntryKind::File, None)
+                }
+            }
+        } else {
+            (metadata.len(), EntryKind::File, None)
+        };
+
+        self.entries.push(FileEntry {
+            relative_path,
+            size,
+            kind,
+            modified: modified_millis(metadata),
+            sha256: None,
+            mode: attributes::mode(metadata),
+            xattrs: None,
+            link_target,
+        });
+        self.sources.push(source);
+    }
+
+    // metadata 为链接本身的元数据
+    fn push_symlink(
+        &mut self,
+        relative_path: String,
+        target: String,
+        metadata: &Metadata,
+        source: PathBuf,
+    ) {
+        self.entries.push(FileEntry {
+            relative_path,
+            size: 0,
+            kind: EntryKind::Symlink,
+            modified: modified_millis(metadata),
+            sha256: None,
+            mode: None,
+            xattrs: None,
+            link_target: Some(target),
+        });
+        self.sources.push(source);
+    }
+}
+
+// 展开选中的文件和文件夹，文件夹按目录结构递归遍历，
+// follow_symlinks 决定文件夹中的符号链接按目标内容还是按链接本身发送
+pub async fn build_manifest(
+    paths: &[String],
+    ignore_patterns: &[String],
+    follow_symlinks: bool,
+) -> Result<Manifest, String> {
+    let mut manifest = Manifest {
+        entries: Vec::new(),
+        sources: Vec::new(),
+        inodes: HashMap::new(),
+    };
+
+    for path in paths {
+        let path = Path::new(path);
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format!("Invalid file name: {}", path.display()))?
+            .to_string();
+
+        let metadata = fs::metadata(path)
+            .await
+            .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+
+        if metadata.is_dir() {
+            walk_directory(
+                path,
+                name,
+                metadata,
+                ignore_patterns,
+                follow_symlinks,
+                &mut manifest,
+            )
+            .await?;
+        } else if metadata.is_file() {
+            manifest.push(name, &metadata, path.to_path_buf());
+        } else {
+            return Err(format!("Unsupported file type: {}", path.display()));
+        }
+    }
+
+    Ok(manifest)
+}
+
+async fn walk_directory(
+    root: &Path,
+    root_name: String,
+    root_metadata: Metadata,
+    ignore_patterns: &[String],
+    follow_symlinks: bool,
+    manifest: &mut Manifest,
+) -> Result<(), String> {
+    // 用栈代替递归，目录项先于其内容加入清单，空目录也会被保留
+    let mut pending = vec![(root.to_path_buf(), root_name, root_metadata)];
+
+    while let Some((dir, relative_dir, dir_metadata)) = pending.pop() {
+        manifest.push(relative_dir.clone(), &dir_metadata, dir.clone());
+
+        let mut read_dir = fs::read_dir(&dir)
+            .await
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+
+        let mut children = Vec::new();
+        while let Some(entry) = read_dir
+            .next_entry()
+            .await
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?
+        {
+            children.push(entry);
+        }
+        children.sort_by_key(|entry| entry.file_name());
+
+        let mut subdirs = Vec::new();
+        for entry in children {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    log::warn!("Skipping non UTF-8 file name: {:?}", name);
+                    continue;
+                }
+            };
+
+            if is_ignored(&name, ignore_patterns) {
+                continue;
+            }
+
+            let path = entry.path();
+            let relative_path = format!("{}/{}", relative_dir, name);
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| format!("Failed to get file type: {}", e))?;
+
+            if file_type.is_dir() || file_type.is_file() {
+                let metadata = entry
+                    .metadata()
+                    .await
+                    .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+                if file_type.is_dir() {
+                    subdirs.push((path, relative_path, metadata));
+                } else {
+                    manifest.push(relative_path, &metadata, path);
+                }
+            } else if file_type.is_symlink() && follow_symlinks {
+                // 跟随指向文件的链接，跳过指向目录的链接以免出现循环
+                match fs::metadata(&path).await {
+                    Ok(metadata) if metadata.is_file() => {
+                        manifest.push(relative_path, &metadata, path);
+                    }
+                    _ => log::warn!("Skipping symlink: {}", path.display()),
+                }
+            } else if file_type.is_symlink() {
+                // 按链接本身发送，目录中的 DirEntry 元数据不跟随链接
+                let metadata = entry
+                    .metadata()
+                    .await
+                    .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+                match links::read_symlink(&path).await {
+                    Ok(target) => manifest.push_symlink(relative_path, target, &metadata, path),
+                    Err(e) => log::warn!("Skipping symlink: {}", e),
+                }
+            }
+        }
+
+        // 逆序入栈，保证子目录按名称顺序出栈
+        pending.extend(subdirs.into_iter().rev());
+    }
+
+    Ok(())
+}
+
+pub fn modified_millis(metadata: &Metadata) -> Option<i64> {
+    let duration = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    i64::try_from(duration.as_millis()).ok()
+}
+
+pub fn is_ignored(name: &str, ignore_patterns: &[String]) -> bool {
+    ignore_patterns
+        .iter()
+        .any(|pattern| matches_pattern(pattern, name))
+}
+
+// 简单的通配符匹配: * 匹配任意字符串, ? 匹配单个字符
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
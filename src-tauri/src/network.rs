@@ -1,9 +1,11 @@
-use crate::device::Device;
+use crate::device::{Device, DeviceManager};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 // Emitter是Tauri 2.0中emit方法所需的trait
 use tauri::{Manager, Emitter};
+use tokio::sync::Mutex;
 use tokio::time;
 
 pub struct NetworkManager {
@@ -21,6 +23,7 @@ impl NetworkManager {
         &mut self,
         app_handle: &tauri::AppHandle,
         current_device: &Device,
+        device_manager: Arc<Mutex<DeviceManager>>,
     ) -> Result<(), String> {
         // 创建 mDNS 服务
         let mdns =
@@ -66,18 +69,33 @@ impl NetworkManager {
 
         tokio::spawn(async move {
             let mut timeout = time::interval(Duration::from_secs(30));
+            // 服务全名到设备 id，离线事件只带服务全名
+            let mut resolved: HashMap<String, String> = HashMap::new();
 
             loop {
                 tokio::select! {
                     event = receiver.recv_async() => {
                         match event {
                             Ok(ServiceEvent::ServiceResolved(info)) => {
+                                let fullname = info.get_fullname().to_string();
                                 if let Some(device) = Self::parse_device_info(info, &current_device_id) {
+                                    // 记录已发现的设备，用于查找发送目标和校验发送方身份
+                                    resolved.insert(fullname, device.id.clone());
+                                    device_manager.lock().await.add_device(device.clone());
                                     let _ = app_handle_clone.emit("device-discovered", &device);
                                 }
                             }
-                            Ok(ServiceEvent::ServiceRemoved(_, _)) => {
-                                // 处理设备离线
+                            Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                                // 处理设备离线: 标记为离线后发送队列不再向它启动任务，界面按 id 更新该设备
+                                if let Some(device_id) = resolved.remove(&fullname) {
+                                    let device = device_manager
+                                        .lock()
+                                        .await
+                                        .update_device_status(&device_id, false);
+                                    if let Some(device) = device {
+                                        let _ = app_handle_clone.emit("device-discovered", &device);
+                                    }
+                                }
                             }
                             Err(e) => {
                                 log::error!("Error receiving mDNS event: {}", e);
@@ -0,0 +1,83 @@
+use crate::resume;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// 接收中的文件先写入同目录下的临时文件，校验通过后再改名
+pub const PART_EXTENSION: &str = "lantransfer-part";
+
+pub fn part_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PART_EXTENSION);
+    target.with_file_name(name)
+}
+
+pub fn is_part_file(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e == PART_EXTENSION)
+        .unwrap_or(false)
+}
+
+// 并行接收前为临时文件预留空间，磁盘不足时在写入之前就失败，也减少碎片。
+// 文件系统不支持预留时只设置文件长度
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+        if result != 0 {
+            let e = io::Error::last_os_error();
+            if !matches!(
+                e.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS)
+            ) {
+                return Err(e);
+            }
+        }
+    }
+    // 预留空间不会截短文件，之前更长的临时文件截到应有的长度
+    file.set_len(len)
+}
+
+// 写到文件的指定位置，不使用文件的读写位置，多个区间可以共用同一个文件句柄
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.write_all_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            let written = file.seek_write(buf, offset)?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf = &buf[written..];
+            offset += written as u64;
+        }
+        Ok(())
+    }
+}
+
+// 删除上次运行遗留且无法续传的临时文件，在后台线程中调用。
+// 只检查续传记录中的保存位置，不遍历接收目录
+pub fn cleanup_stale_part_files() {
+    let mut removed = 0;
+    for path in resume::stale_part_files() {
+        if !is_part_file(&path) {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+
+    if removed > 0 {
+        log::info!("Removed {} stale part files", removed);
+    }
+}
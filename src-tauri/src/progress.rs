@@ -0,0 +1,345 @@
+use crate::manifest::FileEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+
+// 同一传输两次进度事件之间的最短间隔，约 10 Hz
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// 瞬时速度的平滑系数，越大越接近最近一个间隔的速度
+const SPEED_SMOOTHING: f64 = 0.3;
+// 计算校验和需要一段时间的文件，开始计算时立即显示
+const HASH_NOTICE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub peer_id: String,
+    pub file_name: String,
+    // 当前文件在清单中的序号
+    pub file_index: u32,
+    pub file_count: usize,
+    // 当前文件的百分比
+    pub progress: f64,
+    pub status: String,
+    // 整个传输已完成和总共的字节数
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // 字节/秒，speed 为最近的平滑速度，average_speed 为开始以来的平均速度
+    pub speed: f64,
+    pub average_speed: f64,
+    // 按当前速度估算的剩余秒数，速度为 0 时为空
+    pub eta_secs: Option<f64>,
+    // 接收完成时文件实际保存的位置，可能因重名而改名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_path: Option<String>,
+}
+
+// 传输结束时的汇总，随 transfer-finished 一起发送
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProgressSummary {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub average_speed: f64,
+    pub elapsed_secs: f64,
+    // 本次传输的文件内容在连接上实际占用的字节数，压缩率为实际发送的内容与它之比，
+    // 不含稀疏文件的空洞和增量传输中引用旧版本的部分，这两部分单独统计
+    pub wire_bytes: u64,
+    pub compression_ratio: f64,
+    pub hole_bytes: u64,
+    pub reused_bytes: u64,
+    // 按重名策略跳过的文件数，以及接收方已有相同内容而未传输的文件数
+    pub files_skipped: usize,
+    pub files_identical: usize,
+}
+
+struct CurrentFile {
+    index: u32,
+    done: u64,
+    status: &'static str,
+}
+
+// 一次传输的进度，数据块的进度按固定频率合并发送，文件状态变化立即发送
+pub struct ProgressTracker {
+    app_handle: tauri::AppHandle,
+    transfer_id: String,
+    peer_id: String,
+    files: Vec<(String, u64)>,
+    bytes_total: u64,
+    // 已结束的文件及其计入的字节数，重传时会移除
+    finished: HashMap<u32, u64>,
+    finished_bytes: u64,
+    skipped: HashSet<u32>,
+    identical: HashSet<u32>,
+    current: Option<CurrentFile>,
+    // 最近一个打包完成的文件，合并发送进度时显示它
+    batched: Option<u32>,
+    // 本次实际传输的字节数，不含续传跳过的部分
+    transferred: u64,
+    // transferred 中的数据压缩后实际传输的字节数
+    wire: u64,
+    // transferred 中没有经过连接的空洞和旧版本内容
+    hole: u64,
+    reused: u64,
+    started: Instant,
+    last_emit: Instant,
+    // 上次计算速度的时间和当时的 transferred
+    sampled_at: Instant,
+    sampled_bytes: u64,
+    speed: f64,
+    // 有尚未发送的进度
+    dirty: bool,
+}
+
+impl ProgressTracker {
+    pub fn new(
+        app_handle: &tauri::AppHandle,
+        transfer_id: &str,
+        peer_id: &str,
+        files: &[FileEntry],
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            app_handle: app_handle.clone(),
+            transfer_id: transfer_id.to_string(),
+            peer_id: peer_id.to_string(),
+            files: files
+                .iter()
+                .map(|entry| (entry.relative_path.clone(), entry.size))
+                .collect(),
+            bytes_total: files.iter().map(|entry| entry.size).sum(),
+            finished: HashMap::new(),
+            finished_bytes: 0,
+            skipped: HashSet::new(),
+            identical: HashSet::new(),
+            current: None,
+            batched: None,
+            transferred: 0,
+            wire: 0,
+            hole: 0,
+            reused: 0,
+            started: now,
+            last_emit: now,
+            sampled_at: now,
+            sampled_bytes: 0,
+            speed: 0.0,
+            dirty: false,
+        }
+    }
+
+    // 开始发送或接收一个文件，done 为续传时已有的字节数
+    pub fn start_file(&mut self, index: u32, done: u64, status: &'static str) {
+        if let Some(bytes) = self.finished.remove(&index) {
+            self.finished_bytes -= bytes;
+        }
+        self.current = Some(CurrentFile {
+            index,
+            done,
+            status,
+        });
+        self.batched = None;
+        self.emit_current();
+    }
+
+    // 发送前计算文件的校验和，小文件与数据块的进度一样按固定频率合并发送
+    pub fn start_hashing(&mut self, index: u32) {
+        self.current = Some(CurrentFile {
+            index,
+            done: 0,
+            status: "hashing",
+        });
+        self.dirty = true;
+        if self.file_size(index) >= HASH_NOTICE_SIZE
+            || self.last_emit.elapsed() >= PROGRESS_INTERVAL
+        {
+            self.emit_current();
+        }
+    }
+
+    // wire_bytes 为这些数据压缩后在连接上实际占用的字节数
+    pub fn advance_compressed(&mut self, bytes: u64, wire_bytes: u64) {
+        self.wire += wire_bytes;
+        if let Some(current) = &mut self.current {
+            current.done += bytes;
+        }
+        self.transferred += bytes;
+        self.dirty = true;
+        if self.last_emit.elapsed() >= PROGRESS_INTERVAL {
+            self.emit_current();
+        }
+    }
+
+    // 稀疏文件中跳过的空洞，计入进度但不占用连接
+    pub fn advance_hole(&mut self, bytes: u64) {
+        self.hole += bytes;
+        self.advance_compressed(bytes, 0);
+    }
+
+    // 增量传输还原出的 bytes 字节中有 reused 字节引用接收方的旧版本
+    pub fn advance_delta(&mut self, bytes: u64, reused: u64, wire_bytes: u64) {
+        self.reused += reused;
+        self.advance_compressed(bytes, wire_bytes);
+    }
+
+    // 发送方从头重发当前文件
+    pub fn restart_file(&mut self) {
+        if let Some(current) = &mut self.current {
+            current.done = 0;
+        }
+    }
+
+    // 文件已完成或被跳过，计入已完成的字节数
+    pub fn complete_file(&mut self, index: u32, status: &'static str, saved_path: Option<&Path>) {
+        let size = self.file_size(index);
+        if let Some(bytes) = self.finished.insert(index, size) {
+            self.finished_bytes -= bytes;
+        }
+        self.finished_bytes += size;
+        match status {
+            "skipped" => self.skipped.insert(index),
+            "identical" => self.identical.insert(index),
+            _ => false,
+        };
+        self.current = None;
+        self.emit(index, status, 100.0, saved_path);
+    }
+
+    // 打包发送的小文件，数量可能很多，与数据块的进度一样按固定频率合并发送
+    pub fn complete_batched(&mut self, index: u32) {
+        let size = self.file_size(index);
+        if let Some(bytes) = self.finished.insert(index, size) {
+            self.finished_bytes -= bytes;
+        }
+        self.finished_bytes += size;
+        self.current = None;
+        self.batched = Some(index);
+        self.dirty = true;
+        if self.last_emit.elapsed() >= PROGRESS_INTERVAL {
+            self.emit_current();
+        }
+    }
+
+    // 文件失败或校验不一致，不计入已完成的字节数
+    pub fn fail_file(&mut self, index: u32, status: &'static str) {
+        self.current = None;
+        self.emit(index, status, 0.0, None);
+    }
+
+    // 传输结束前把被合并掉的最后一次进度发出去，并返回汇总
+    pub fn finish(&mut self) -> ProgressSummary {
+        if self.dirty {
+            self.emit_current();
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        ProgressSummary {
+            bytes_done: self.bytes_done(),
+            bytes_total: self.bytes_total,
+            average_speed: self.average_speed(),
+            elapsed_secs: elapsed,
+            wire_bytes: self.wire,
+            compression_ratio: if self.wire > 0 {
+                (self.transferred - self.hole - self.reused) as f64 / self.wire as f64
+            } else {
+                1.0
+            },
+            hole_bytes: self.hole,
+            reused_bytes: self.reused,
+            files_skipped: self.skipped.len(),
+            files_identical: self.identical.len(),
+        }
+    }
+
+    fn file_size(&self, index: u32) -> u64 {
+        self.files
+            .get(index as usize)
+            .map(|(_, size)| *size)
+            .unwrap_or(0)
+    }
+
+    fn bytes_done(&self) -> u64 {
+        let current = self.current.as_ref().map(|c| c.done).unwrap_or(0);
+        (self.finished_bytes + current).min(self.bytes_total)
+    }
+
+    fn average_speed(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.transferred as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    // 间隔太短时速度波动大，至少隔一个发送周期才重新计算
+    fn update_speed(&mut self) {
+        let interval = self.sampled_at.elapsed();
+        if interval < PROGRESS_INTERVAL {
+            return;
+        }
+        let instant = (self.transferred - self.sampled_bytes) as f64 / interval.as_secs_f64();
+        // 暂停或等待对端时速度逐渐降为 0
+        self.speed = if self.speed > 0.0 {
+            self.speed + (instant - self.speed) * SPEED_SMOOTHING
+        } else {
+            instant
+        };
+        self.sampled_at = Instant::now();
+        self.sampled_bytes = self.transferred;
+    }
+
+    fn emit_current(&mut self) {
+        let Some(current) = &self.current else {
+            if let Some(index) = self.batched {
+                self.emit(index, "completed", 100.0, None);
+            }
+            return;
+        };
+        let (index, status) = (current.index, current.status);
+        let size = self.file_size(index);
+        let progress = if size > 0 {
+            (current.done as f64 / size as f64 * 100.0).min(100.0)
+        } else {
+            0.0
+        };
+        self.emit(index, status, progress, None);
+    }
+
+    fn emit(&mut self, index: u32, status: &str, progress: f64, saved_path: Option<&Path>) {
+        self.update_speed();
+        self.last_emit = Instant::now();
+        self.dirty = false;
+
+        let file_name = self
+            .files
+            .get(index as usize)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_default();
+        let bytes_done = self.bytes_done();
+        let remaining = self.bytes_total.saturating_sub(bytes_done);
+        let eta_secs = if remaining == 0 {
+            Some(0.0)
+        } else if self.speed > 0.0 {
+            Some(remaining as f64 / self.speed)
+        } else {
+            None
+        };
+
+        let progress = TransferProgress {
+            transfer_id: self.transfer_id.clone(),
+            peer_id: self.peer_id.clone(),
+            file_name,
+            file_index: index,
+            file_count: self.files.len(),
+            progress,
+            status: status.to_string(),
+            bytes_done,
+            bytes_total: self.bytes_total,
+            speed: self.speed,
+            average_speed: self.average_speed(),
+            eta_secs,
+            saved_path: saved_path.map(|path| path.display().to_string()),
+        };
+        let _ = self.app_handle.emit("transfer-progress", &progress);
+    }
+}
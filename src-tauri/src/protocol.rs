@@ -0,0 +1,746 @@
+use crate::compression::{Codec, Compressor};
+use crate::ratelimit::Throttle;
+use crate::sendfile;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// 每个连接开头双方都要先发送的魔数和帧格式版本，帧格式不兼容时无法继续解析
+pub const MAGIC: &[u8; 4] = b"LNTR";
+pub const FRAMING_VERSION: u16 = 1;
+
+// 会话协议版本，在 Hello 帧中协商
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// 本端支持的能力，按优先级排列
+const SUPPORTED_COMPRESSION: &[&str] = &["zstd", "lz4", "none"];
+const SUPPORTED_ENCRYPTION: &[&str] = &["none"];
+const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_RESUME,
+    FEATURE_CONTROL,
+    FEATURE_BATCH,
+    FEATURE_DELTA,
+    FEATURE_LINKS,
+    FEATURE_SPARSE,
+];
+
+// 接收方在 Accept 中给出断点，发送方校验前缀后从断点继续发送
+pub const FEATURE_RESUME: &str = "resume";
+// 传输过程中双方可以发送 Control 帧暂停、继续或取消
+pub const FEATURE_CONTROL: &str = "control";
+// 小文件打包成 Batch 帧发送，不再逐个发送 Data 帧和结尾帧
+pub const FEATURE_BATCH: &str = "batch";
+// 接收方已有旧版本的大文件，按接收方的块签名只发送变化的部分
+pub const FEATURE_DELTA: &str = "delta";
+// 清单中可以有符号链接和硬链接，不支持的接收方无法解析这样的清单
+pub const FEATURE_LINKS: &str = "links";
+// 稀疏文件只发送有数据的区间，Data 帧的偏移可以跳过空洞，结尾的空洞由结尾帧的大小补齐
+pub const FEATURE_SPARSE: &str = "sparse";
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+// 大文件最多拆分到的并行数据连接数
+const MAX_STREAMS: u32 = 8;
+
+// 帧头: 1 字节帧类型 + 4 字节大端长度
+const FRAME_HEADER_LEN: usize = 5;
+// Data 帧负载头: 4 字节文件序号 + 8 字节文件内偏移
+const DATA_HEADER_LEN: usize = 12;
+// Compressed 帧负载头: Data 帧负载头 + 4 字节原始长度
+const COMPRESSED_HEADER_LEN: usize = DATA_HEADER_LEN + 4;
+// 单帧最大长度，防止对端发送超大长度耗尽内存
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+// 后台读取任务最多缓存的帧数，处理不过来时由 TCP 反压对端
+const FRAME_QUEUE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Hello,
+    Offer,
+    Accept,
+    Data,
+    Ack,
+    Error,
+    Close,
+    FileEnd,
+    Control,
+    Join,
+    Stripe,
+    Compressed,
+    Batch,
+    Signature,
+    Delta,
+}
+
+impl FrameType {
+    fn to_u8(self) -> u8 {
+        match self {
+            FrameType::Hello => 1,
+            FrameType::Offer => 2,
+            FrameType::Accept => 3,
+            FrameType::Data => 4,
+            FrameType::Ack => 5,
+            FrameType::Error => 6,
+            FrameType::Close => 7,
+            FrameType::FileEnd => 8,
+            FrameType::Control => 9,
+            FrameType::Join => 10,
+            FrameType::Stripe => 11,
+            FrameType::Compressed => 12,
+            FrameType::Batch => 13,
+            FrameType::Signature => 14,
+            FrameType::Delta => 15,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FrameType::Hello),
+            2 => Some(FrameType::Offer),
+            3 => Some(FrameType::Accept),
+            4 => Some(FrameType::Data),
+            5 => Some(FrameType::Ack),
+            6 => Some(FrameType::Error),
+            7 => Some(FrameType::Close),
+            8 => Some(FrameType::FileEnd),
+            9 => Some(FrameType::Control),
+            10 => Some(FrameType::Join),
+            11 => Some(FrameType::Stripe),
+            12 => Some(FrameType::Compressed),
+            13 => Some(FrameType::Batch),
+            14 => Some(FrameType::Signature),
+            15 => Some(FrameType::Delta),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, payload: Vec<u8>) -> Self {
+        Self {
+            frame_type,
+            payload,
+        }
+    }
+
+    // Data 帧负载: 文件在清单中的序号、文件内偏移，之后是文件内容
+    pub fn data(file_index: u32, offset: u64, bytes: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(DATA_HEADER_LEN + bytes.len());
+        payload.extend_from_slice(&file_index.to_be_bytes());
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(bytes);
+        Self::new(FrameType::Data, payload)
+    }
+
+    // Data 帧的帧头和负载头，负载为 len 字节的文件内容
+    fn data_header(file_index: u32, offset: u64, len: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(FRAME_HEADER_LEN + DATA_HEADER_LEN);
+        header.push(FrameType::Data.to_u8());
+        header.extend_from_slice(&((DATA_HEADER_LEN + len) as u32).to_be_bytes());
+        header.extend_from_slice(&file_index.to_be_bytes());
+        header.extend_from_slice(&offset.to_be_bytes());
+        header
+    }
+
+    // Compressed 帧负载: 与 Data 帧相同的负载头和原始长度，之后是压缩后的文件内容
+    pub fn compressed(file_index: u32, offset: u64, raw_len: usize, compressed: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(COMPRESSED_HEADER_LEN + compressed.len());
+        payload.extend_from_slice(&file_index.to_be_bytes());
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&(raw_len as u32).to_be_bytes());
+        payload.extend_from_slice(compressed);
+        Self::new(FrameType::Compressed, payload)
+    }
+
+    // 把 Compressed 帧还原成 Data 帧，其他帧原样返回，
+    // 同时返回文件内容在连接上实际占用的字节数
+    pub fn decompress(self, codec: Option<Codec>) -> Result<(Self, usize), String> {
+        if self.frame_type != FrameType::Compressed {
+            let wire = self.payload.len().saturating_sub(DATA_HEADER_LEN);
+            return Ok((self, wire));
+        }
+        let codec = codec.ok_or("Peer sent compressed data without negotiating compression")?;
+        if self.payload.len() < COMPRESSED_HEADER_LEN {
+            return Err("Compressed frame too short".to_string());
+        }
+
+        let (header, compressed) = self.payload.split_at(COMPRESSED_HEADER_LEN);
+        let mut raw_len = [0u8; 4];
+        raw_len.copy_from_slice(&header[DATA_HEADER_LEN..]);
+        let raw_len = u32::from_be_bytes(raw_len) as usize;
+        if raw_len > MAX_FRAME_LEN - DATA_HEADER_LEN {
+            return Err(format!("Compressed chunk too large: {} bytes", raw_len));
+        }
+
+        let raw = codec.decompress(compressed, raw_len)?;
+        let mut payload = Vec::with_capacity(DATA_HEADER_LEN + raw.len());
+        payload.extend_from_slice(&header[..DATA_HEADER_LEN]);
+        payload.extend_from_slice(&raw);
+        Ok((Self::new(FrameType::Data, payload), compressed.len()))
+    }
+
+    pub fn parse_data(&self) -> Result<DataChunk<'_>, String> {
+        if self.payload.len() < DATA_HEADER_LEN {
+            return Err("Data frame too short".to_string());
+        }
+
+        let (header, bytes) = self.payload.split_at(DATA_HEADER_LEN);
+        let mut index = [0u8; 4];
+        let mut offset = [0u8; 8];
+        index.copy_from_slice(&header[..4]);
+        offset.copy_from_slice(&header[4..]);
+
+        Ok(DataChunk {
+            file_index: u32::from_be_bytes(index),
+            offset: u64::from_be_bytes(offset),
+            bytes,
+        })
+    }
+
+    // 控制帧的负载统一使用 JSON
+    pub fn json<T: Serialize>(frame_type: FrameType, message: &T) -> Result<Self, String> {
+        let payload = serde_json::to_vec(message)
+            .map_err(|e| format!("Failed to serialize {:?} frame: {}", frame_type, e))?;
+        Ok(Self::new(frame_type, payload))
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_slice(&self.payload)
+            .map_err(|e| format!("Failed to parse {:?} frame: {}", self.frame_type, e))
+    }
+
+    // 检查帧类型，对端发来 Error 帧时直接转换成错误信息
+    pub fn expect(self, frame_type: FrameType) -> Result<Self, String> {
+        if self.frame_type == frame_type {
+            return Ok(self);
+        }
+
+        if self.frame_type == FrameType::Error {
+            let error: ErrorMessage = self.parse()?;
+            return Err(format!("Peer reported error: {}", error.message));
+        }
+
+        Err(format!(
+            "Unexpected frame: expected {:?}, got {:?}",
+            frame_type, self.frame_type
+        ))
+    }
+}
+
+pub struct DataChunk<'a> {
+    pub file_index: u32,
+    pub offset: u64,
+    pub bytes: &'a [u8],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub compression: Vec<String>,
+    pub encryption: Vec<String>,
+    pub max_chunk_size: u32,
+    pub features: Vec<String>,
+    // 旧版本没有该字段，视为只支持单个连接
+    #[serde(default)]
+    pub max_streams: u32,
+}
+
+impl Capabilities {
+    pub fn local() -> Self {
+        Self {
+            compression: SUPPORTED_COMPRESSION
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            encryption: SUPPORTED_ENCRYPTION.iter().map(|s| s.to_string()).collect(),
+            max_chunk_size: MAX_CHUNK_SIZE,
+            features: SUPPORTED_FEATURES.iter().map(|s| s.to_string()).collect(),
+            max_streams: MAX_STREAMS,
+        }
+    }
+}
+
+// 协商后双方共同使用的参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionParams {
+    pub protocol_version: u16,
+    pub compression: String,
+    pub encryption: String,
+    pub chunk_size: u32,
+    pub features: Vec<String>,
+    // 大文件可以使用的并行数据连接数，不超过 1 时不拆分
+    #[serde(default)]
+    pub streams: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloMessage {
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+    pub app_version: String,
+    pub capabilities: Capabilities,
+    // 只有接收方的回复会携带协商结果
+    #[serde(default)]
+    pub session: Option<SessionParams>,
+}
+
+impl HelloMessage {
+    pub fn local() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: Capabilities::local(),
+            session: None,
+        }
+    }
+}
+
+// 根据双方的 Hello 选出共同支持的最佳参数，由接收方调用
+pub fn negotiate(local: &HelloMessage, peer: &HelloMessage) -> Result<SessionParams, String> {
+    let protocol_version = local.protocol_version.min(peer.protocol_version);
+    if protocol_version < local.min_protocol_version || protocol_version < peer.min_protocol_version
+    {
+        return Err(format!(
+            "Incompatible app versions: peer {} speaks protocol v{}-v{}, this device speaks v{}-v{}. Please update both devices",
+            peer.app_version,
+            peer.min_protocol_version,
+            peer.protocol_version,
+            local.min_protocol_version,
+            local.protocol_version
+        ));
+    }
+
+    let compression = pick_common(
+        &local.capabilities.compression,
+        &peer.capabilities.compression,
+    )
+    .ok_or_else(|| {
+        format!(
+            "No common compression codec: peer supports {:?}, this device supports {:?}",
+            peer.capabilities.compression, local.capabilities.compression
+        )
+    })?;
+
+    let encryption = pick_common(
+        &local.capabilities.encryption,
+        &peer.capabilities.encryption,
+    )
+    .ok_or_else(|| {
+        format!(
+            "No common encryption mode: peer supports {:?}, this device supports {:?}",
+            peer.capabilities.encryption, local.capabilities.encryption
+        )
+    })?;
+
+    let chunk_size = local
+        .capabilities
+        .max_chunk_size
+        .min(peer.capabilities.max_chunk_size);
+    if chunk_size == 0 {
+        return Err("Peer announced a zero chunk size".to_string());
+    }
+
+    let features = local
+        .capabilities
+        .features
+        .iter()
+        .filter(|f| peer.capabilities.features.contains(f))
+        .cloned()
+        .collect();
+
+    let streams = local
+        .capabilities
+        .max_streams
+        .min(peer.capabilities.max_streams)
+        .max(1);
+
+    Ok(SessionParams {
+        protocol_version,
+        compression,
+        encryption,
+        chunk_size,
+        features,
+        streams,
+    })
+}
+
+// 按本端优先级选出第一个双方都支持的选项
+fn pick_common(local: &[String], peer: &[String]) -> Option<String> {
+    local.iter().find(|item| peer.contains(item)).cloned()
+}
+
+// 发送方校验接收方选定的参数确实在本端能力范围内
+pub fn validate_session(local: &HelloMessage, session: &SessionParams) -> Result<(), String> {
+    let caps = &local.capabilities;
+    if session.protocol_version < local.min_protocol_version
+        || session.protocol_version > local.protocol_version
+        || !caps.compression.contains(&session.compression)
+        || !caps.encryption.contains(&session.encryption)
+        || session.chunk_size == 0
+        || session.chunk_size > caps.max_chunk_size
+        || session.features.iter().any(|f| !caps.features.contains(f))
+        || session.streams > caps.max_streams
+    {
+        return Err(format!(
+            "Peer selected unsupported session parameters: {:?}",
+            session
+        ));
+    }
+    Ok(())
+}
+
+// 每个文件数据之后的结尾帧，携带发送方边读边算的校验和
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEndMessage {
+    pub file_index: u32,
+    pub size: u64,
+    pub sha256: String,
+    // 拆分发送的文件没有整体校验和，按 Stripe 帧中的区间顺序给出每段的校验和
+    #[serde(default)]
+    pub ranges: Vec<String>,
+}
+
+// 数据连接建立后代替 Hello 发送，表明该连接属于哪个传输
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinMessage {
+    pub transfer_id: String,
+    pub stream_index: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub len: u64,
+}
+
+// 在控制连接上宣布一个文件拆分发送，第 i 个区间由第 i 个数据连接发送
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StripeMessage {
+    pub file_index: u32,
+    pub ranges: Vec<ByteRange>,
+    // 发送方不计算各区间的校验和，接收方写完后按结尾帧中的整体校验和核对
+    #[serde(default)]
+    pub whole_file: bool,
+}
+
+// 校验失败的文件最多重传的轮数
+pub const MAX_CHECKSUM_RETRIES: usize = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckMessage {
+    pub files_received: u32,
+    pub bytes_received: u64,
+    // 校验失败、需要重传的文件序号
+    #[serde(default)]
+    pub retry: Vec<u32>,
+    // 重传后仍然校验失败的文件序号
+    #[serde(default)]
+    pub failed: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlMessage {
+    pub action: ControlAction,
+}
+
+// 错误码，方便界面区分错误类型
+pub const ERROR_INCOMPATIBLE: &str = "incompatible";
+pub const ERROR_INTERNAL: &str = "internal";
+pub const ERROR_CHECKSUM_MISMATCH: &str = "checksum_mismatch";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    #[serde(default)]
+    pub code: String,
+    pub message: String,
+}
+
+pub async fn write_preamble<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<(), String> {
+    let mut preamble = Vec::with_capacity(6);
+    preamble.extend_from_slice(MAGIC);
+    preamble.extend_from_slice(&FRAMING_VERSION.to_be_bytes());
+
+    writer
+        .write_all(&preamble)
+        .await
+        .map_err(|e| format!("Failed to send protocol header: {}", e))
+}
+
+// 读取并校验对端的协议头，返回对端的帧格式版本
+pub async fn read_preamble<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u16, String> {
+    let mut preamble = [0u8; 6];
+    reader
+        .read_exact(&mut preamble)
+        .await
+        .map_err(|e| format!("Failed to read protocol header: {}", e))?;
+
+    if &preamble[..4] != MAGIC {
+        return Err("Peer is not a LANTransfer client".to_string());
+    }
+
+    let version = u16::from_be_bytes([preamble[4], preamble[5]]);
+    if version != FRAMING_VERSION {
+        return Err(format!(
+            "Incompatible frame format: peer uses v{}, this build uses v{}. Please update both devices",
+            version, FRAMING_VERSION
+        ));
+    }
+
+    Ok(version)
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), String> {
+    if frame.payload.len() > MAX_FRAME_LEN {
+        return Err(format!(
+            "Frame too large: {} bytes (max {})",
+            frame.payload.len(),
+            MAX_FRAME_LEN
+        ));
+    }
+
+    // 帧头和负载合并成一次写入，避免 Nagle 算法造成的延迟
+    let mut buffer = Vec::with_capacity(FRAME_HEADER_LEN + frame.payload.len());
+    buffer.push(frame.frame_type.to_u8());
+    buffer.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&frame.payload);
+
+    writer
+        .write_all(&buffer)
+        .await
+        .map_err(|e| format!("Failed to send {:?} frame: {}", frame.frame_type, e))
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, String> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| format!("Failed to read frame header: {}", e))?;
+
+    let frame_type = FrameType::from_u8(header[0])
+        .ok_or_else(|| format!("Unknown frame type: {}", header[0]))?;
+    let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+
+    if length > MAX_FRAME_LEN {
+        return Err(format!(
+            "Frame too large: {} bytes (max {})",
+            length, MAX_FRAME_LEN
+        ));
+    }
+
+    let mut payload = vec![0; length];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| format!("Failed to read {:?} frame: {}", frame_type, e))?;
+
+    Ok(Frame::new(frame_type, payload))
+}
+
+// 出错时尽量通知对端，发送失败也不影响原始错误的返回
+pub async fn send_error<W: AsyncWrite + Unpin>(writer: &mut W, code: &str, message: &str) {
+    let error = ErrorMessage {
+        code: code.to_string(),
+        message: message.to_string(),
+    };
+    if let Ok(frame) = Frame::json(FrameType::Error, &error) {
+        let _ = write_frame(writer, &frame).await;
+    }
+}
+
+// 握手之后把连接拆成读写两半，读取放在后台任务中，
+// 这样等待对端帧的同时还能响应本地的控制指令
+pub struct Connection {
+    writer: OwnedWriteHalf,
+    frames: mpsc::Receiver<Result<Frame, String>>,
+    // 发送文件数据时的限速
+    throttle: Option<Throttle>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        let (mut reader, writer) = stream.into_split();
+        let (frame_tx, frames) = mpsc::channel(FRAME_QUEUE_LEN);
+
+        tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut reader).await;
+                let failed = frame.is_err();
+                if frame_tx.send(frame).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            writer,
+            frames,
+            throttle: None,
+        }
+    }
+
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = Some(throttle);
+    }
+
+    // 发送了 bytes 字节的文件数据，超出限速时等待，控制帧不受限速影响
+    pub async fn throttle(&self, bytes: usize) {
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(bytes as u64).await;
+        }
+    }
+
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        write_frame(&mut self.writer, frame).await
+    }
+
+    pub async fn recv(&mut self) -> Result<Frame, String> {
+        self.frames
+            .recv()
+            .await
+            .unwrap_or_else(|| Err("Connection closed".to_string()))
+    }
+
+    // 发送文件中 offset 开始的一段数据，bytes 是已经从 file 读出的同一段内容，
+    // compressor 认为值得压缩时发送 Compressed 帧，否则 use_sendfile 时由内核从页缓存发送，
+    // 不再复制到帧缓冲区。超出限速时发送后等待，返回文件内容在连接上实际占用的字节数
+    pub async fn send_file_data(
+        &mut self,
+        file_index: u32,
+        offset: u64,
+        file: &fs::File,
+        bytes: &[u8],
+        use_sendfile: bool,
+        compressor: Option<&mut Compressor>,
+    ) -> Result<usize, String> {
+        if let Some(compressed) = compressor.and_then(|c| c.compress(bytes)) {
+            let frame = Frame::compressed(file_index, offset, bytes.len(), &compressed);
+            self.send(&frame).await?;
+            self.throttle(compressed.len()).await;
+            return Ok(compressed.len());
+        }
+        if !use_sendfile {
+            self.send(&Frame::data(file_index, offset, bytes)).await?;
+        } else {
+            let header = Frame::data_header(file_index, offset, bytes.len());
+            sendfile::send_data(&mut self.writer, &header, file, offset, bytes.len()).await?;
+        }
+        self.throttle(bytes.len()).await;
+        Ok(bytes.len())
+    }
+
+    // 由内核把文件中 offset 开始的 len 字节作为一个数据帧发送，数据不经过用户态
+    pub async fn send_file_range(
+        &mut self,
+        file_index: u32,
+        offset: u64,
+        file: &fs::File,
+        len: usize,
+    ) -> Result<usize, String> {
+        let header = Frame::data_header(file_index, offset, len);
+        sendfile::send_data(&mut self.writer, &header, file, offset, len).await?;
+        self.throttle(len).await;
+        Ok(len)
+    }
+
+    // 用于读取套接字选项和地址，数据只能通过 send 和 recv 读写
+    pub fn stream(&self) -> &TcpStream {
+        self.writer.as_ref()
+    }
+
+    // 不等待，只取出已经到达的帧
+    pub fn try_recv(&mut self) -> Option<Result<Frame, String>> {
+        self.frames.try_recv().ok()
+    }
+
+    pub async fn send_error(&mut self, code: &str, message: &str) {
+        send_error(&mut self.writer, code, message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_protocol_version: u16, protocol_version: u16) -> HelloMessage {
+        HelloMessage {
+            protocol_version,
+            min_protocol_version,
+            ..HelloMessage::local()
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_common_capabilities() {
+        let local = HelloMessage::local();
+        let mut peer = HelloMessage::local();
+        peer.capabilities.compression = vec!["lz4".to_string(), "none".to_string()];
+        peer.capabilities.max_chunk_size = 64 * 1024;
+        peer.capabilities.max_streams = 2;
+        peer.capabilities.features = vec![
+            "unknown".to_string(),
+            FEATURE_BATCH.to_string(),
+            FEATURE_RESUME.to_string(),
+        ];
+
+        let session = negotiate(&local, &peer).unwrap();
+        assert_eq!(session.compression, "lz4");
+        assert_eq!(session.encryption, "none");
+        assert_eq!(session.chunk_size, 64 * 1024);
+        assert_eq!(session.streams, 2);
+        // 只保留双方都支持的功能，顺序与本端一致
+        assert_eq!(session.features, vec![FEATURE_RESUME, FEATURE_BATCH]);
+    }
+
+    #[test]
+    fn negotiate_treats_missing_streams_as_one() {
+        let mut peer = HelloMessage::local();
+        peer.capabilities.max_streams = 0;
+        let session = negotiate(&HelloMessage::local(), &peer).unwrap();
+        assert_eq!(session.streams, 1);
+    }
+
+    #[test]
+    fn negotiate_uses_highest_shared_version() {
+        let session = negotiate(&hello(1, 3), &hello(2, 5)).unwrap();
+        assert_eq!(session.protocol_version, 3);
+    }
+
+    #[test]
+    fn negotiate_rejects_version_mismatch() {
+        let error = negotiate(&hello(1, 1), &hello(2, 3)).unwrap_err();
+        assert!(error.starts_with("Incompatible app versions"), "{}", error);
+        assert!(negotiate(&hello(2, 3), &hello(1, 1)).is_err());
+    }
+
+    #[test]
+    fn negotiate_rejects_missing_common_codec() {
+        let mut peer = HelloMessage::local();
+        peer.capabilities.compression = vec!["brotli".to_string()];
+        let error = negotiate(&HelloMessage::local(), &peer).unwrap_err();
+        assert!(
+            error.starts_with("No common compression codec"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use crate::device::Device;
+use crate::settings;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const QUEUE_FILE: &str = "queue.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// 等待发送或正在发送的任务，应用重启后重新排队
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueueEntry {
+    pub transfer_id: String,
+    pub peer: Device,
+    pub file_paths: Vec<String>,
+    #[serde(default)]
+    pub priority: TransferPriority,
+    pub added: DateTime<Local>,
+}
+
+impl QueueEntry {
+    pub fn new(peer: Device, file_paths: Vec<String>, priority: TransferPriority) -> Self {
+        Self {
+            transfer_id: Uuid::new_v4().to_string(),
+            peer,
+            file_paths,
+            priority,
+            added: Local::now(),
+        }
+    }
+
+    // 设备 id 保存在对端的数据目录中，重启后不变；设备名可能重复，不能用来匹配
+    pub fn matches(&self, device: &Device) -> bool {
+        device.id == self.peer.id
+    }
+}
+
+pub fn load() -> Vec<QueueEntry> {
+    settings::app_data_dir()
+        .ok()
+        .and_then(|dir| std::fs::read_to_string(dir.join(QUEUE_FILE)).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn save(entries: &[QueueEntry]) -> Result<(), String> {
+    let dir = settings::app_data_dir()?;
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    let content = serde_json::to_string(entries)
+        .map_err(|e| format!("Failed to serialize transfer queue: {}", e))?;
+    std::fs::write(dir.join(QUEUE_FILE), content)
+        .map_err(|e| format!("Failed to write transfer queue: {}", e))
+}
@@ -0,0 +1,321 @@
+use crate::settings::TransferSettings;
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// 令牌桶最多积攒的时间，空闲一段时间后允许短暂超出限速
+const BURST_TIME: Duration = Duration::from_millis(200);
+// 限速很低时也至少能攒够一个小数据块
+const MIN_BURST: f64 = 64.0 * 1024.0;
+
+// 在这段时间内自动使用更低的总限速，start 晚于 end 时表示跨过午夜
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuietHours {
+    pub enabled: bool,
+    // 本地时间，格式为 HH:MM
+    pub start: String,
+    pub end: String,
+    // 字节/秒，为 0 时不限速
+    pub bandwidth_limit: u64,
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            start: "09:00".to_string(),
+            end: "18:00".to_string(),
+            bandwidth_limit: 1024 * 1024,
+        }
+    }
+}
+
+impl QuietHours {
+    fn contains(&self, now: NaiveTime) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let parse = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").ok();
+        let (Some(start), Some(end)) = (parse(&self.start), parse(&self.end)) else {
+            return false;
+        };
+        if start <= end {
+            now >= start && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+// set_bandwidth_limit 命令调整的范围
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "scope", rename_all = "lowercase")]
+pub enum BandwidthScope {
+    Global,
+    Device { device_id: String },
+    Transfer { transfer_id: String },
+}
+
+// 两个限速中更严格的一个，0 表示不限速
+fn stricter(a: u64, b: u64) -> u64 {
+    match (a, b) {
+        (0, limit) | (limit, 0) => limit,
+        (a, b) => a.min(b),
+    }
+}
+
+struct Bucket {
+    // 字节/秒，为 0 时不限速
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let mut bucket = Self {
+            rate,
+            tokens: 0.0,
+            updated: Instant::now(),
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.rate as f64 * BURST_TIME.as_secs_f64()).max(MIN_BURST)
+    }
+
+    // 限速调整后立即生效，已经积攒的令牌不超过新的上限
+    fn set_rate(&mut self, rate: u64) {
+        if rate != self.rate {
+            self.refill();
+            self.rate = rate;
+            self.tokens = self.tokens.min(self.capacity());
+        }
+    }
+
+    // 令牌已经攒满的桶与新建的桶没有区别
+    fn is_idle(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity()
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+        self.updated = now;
+    }
+
+    // 取出 bytes 个令牌，不够时记为欠账，返回还清欠账需要等待的时间
+    fn take(&mut self, bytes: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Limits {
+    global: u64,
+    devices: HashMap<String, u64>,
+    transfers: HashMap<String, u64>,
+    quiet_hours: QuietHours,
+}
+
+struct Buckets {
+    global: Bucket,
+    devices: HashMap<String, Bucket>,
+    transfers: HashMap<String, Bucket>,
+    quiet: bool,
+}
+
+// 发送数据的总限速、每台设备的限速和每个传输的限速，各用一个令牌桶，
+// 数据要同时满足三者才能发送
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: Arc<Mutex<Limits>>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            limits: Arc::default(),
+            buckets: Arc::new(Mutex::new(Buckets {
+                global: Bucket::new(0),
+                devices: HashMap::new(),
+                transfers: HashMap::new(),
+                quiet: false,
+            })),
+        }
+    }
+}
+
+impl RateLimiter {
+    // 设置变化后调用，正在进行的传输从下一个数据块开始使用新的限速
+    pub fn configure(&self, settings: &TransferSettings) {
+        let mut limits = self.lock_limits();
+        limits.global = settings.bandwidth_limit;
+        limits.devices = settings.device_bandwidth_limits.clone();
+        limits.quiet_hours = settings.quiet_hours.clone();
+    }
+
+    // 单个传输的限速只在本次运行中有效，0 表示取消
+    pub fn set_transfer_limit(&self, transfer_id: &str, limit: u64) {
+        let mut limits = self.lock_limits();
+        if limit == 0 {
+            limits.transfers.remove(transfer_id);
+        } else {
+            limits.transfers.insert(transfer_id.to_string(), limit);
+        }
+    }
+
+    // 传输结束后清除它单独的限速，并丢掉已经空闲的设备令牌桶，
+    // 需要时按当前限速重新创建
+    pub fn finish(&self, transfer_id: &str) {
+        self.lock_limits().transfers.remove(transfer_id);
+        let mut buckets = self.lock_buckets();
+        buckets.transfers.remove(transfer_id);
+        buckets.devices.retain(|_, bucket| !bucket.is_idle());
+    }
+
+    pub fn throttle(&self, transfer_id: &str, device_id: &str) -> Throttle {
+        Throttle {
+            limiter: self.clone(),
+            transfer_id: transfer_id.to_string(),
+            device_id: device_id.to_string(),
+        }
+    }
+
+    // 从三个令牌桶取出 bytes 个令牌，返回需要等待的时间
+    fn reserve(&self, transfer_id: &str, device_id: &str, bytes: u64) -> Duration {
+        let (global, device, transfer, quiet) = {
+            let limits = self.lock_limits();
+            let quiet = limits.quiet_hours.contains(chrono::Local::now().time());
+            let global = if quiet {
+                stricter(limits.global, limits.quiet_hours.bandwidth_limit)
+            } else {
+                limits.global
+            };
+            (
+                global,
+                limits.devices.get(device_id).copied().unwrap_or(0),
+                limits.transfers.get(transfer_id).copied().unwrap_or(0),
+                quiet,
+            )
+        };
+
+        let mut guard = self.lock_buckets();
+        let buckets = &mut *guard;
+        if quiet != buckets.quiet {
+            buckets.quiet = quiet;
+            log::info!(
+                "Quiet hours {}, global bandwidth limit is now {} bytes/s",
+                if quiet { "started" } else { "ended" },
+                global
+            );
+        }
+        buckets.global.set_rate(global);
+        let mut wait = buckets.global.take(bytes);
+        for (buckets, key, rate) in [
+            (&mut buckets.devices, device_id, device),
+            (&mut buckets.transfers, transfer_id, transfer),
+        ] {
+            let bucket = buckets
+                .entry(key.to_string())
+                .or_insert_with(|| Bucket::new(rate));
+            bucket.set_rate(rate);
+            wait = wait.max(bucket.take(bytes));
+        }
+        wait
+    }
+
+    fn lock_limits(&self) -> std::sync::MutexGuard<'_, Limits> {
+        self.limits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_buckets(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// 一个发送任务使用的限速，并行连接共用同一组令牌桶
+#[derive(Clone)]
+pub struct Throttle {
+    limiter: RateLimiter,
+    transfer_id: String,
+    device_id: String,
+}
+
+impl Throttle {
+    // 发送 bytes 字节之后调用，超出限速时等待
+    pub async fn consume(&self, bytes: u64) {
+        let wait = self
+            .limiter
+            .reserve(&self.transfer_id, &self.device_id, bytes);
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_hours(start: &str, end: &str) -> QuietHours {
+        QuietHours {
+            enabled: true,
+            start: start.to_string(),
+            end: end.to_string(),
+            ..QuietHours::default()
+        }
+    }
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_cross_midnight() {
+        let night = quiet_hours("22:00", "06:00");
+        assert!(night.contains(at("22:00")));
+        assert!(night.contains(at("23:59")));
+        assert!(night.contains(at("00:00")));
+        assert!(night.contains(at("05:59")));
+        assert!(!night.contains(at("06:00")));
+        assert!(!night.contains(at("12:00")));
+        assert!(!night.contains(at("21:59")));
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let day = quiet_hours("09:00", "18:00");
+        assert!(day.contains(at("09:00")));
+        assert!(day.contains(at("17:59")));
+        assert!(!day.contains(at("18:00")));
+        assert!(!day.contains(at("03:00")));
+    }
+
+    #[test]
+    fn quiet_hours_disabled_or_invalid() {
+        let mut disabled = quiet_hours("00:00", "23:59");
+        disabled.enabled = false;
+        assert!(!disabled.contains(at("12:00")));
+        assert!(!quiet_hours("25:00", "06:00").contains(at("01:00")));
+        assert!(!quiet_hours("22:00", "").contains(at("23:00")));
+    }
+}
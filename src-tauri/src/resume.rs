@@ -0,0 +1,213 @@
+use crate::collision::CollisionPolicy;
+use crate::manifest::FileEntry;
+use crate::partfile;
+use crate::settings;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// 中断的接收任务，记录已经落盘的进度，重连或重启后从断点继续
+const RESUME_FILE: &str = "resume.json";
+// 超过该天数未续传的记录连同临时文件一起清理
+const RESUME_EXPIRY_DAYS: i64 = 7;
+
+static RESUME_LOCK: Mutex<()> = Mutex::new(());
+
+// 临时文件中已确认写入的前缀及其 SHA-256
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartialFile {
+    pub relative_path: String,
+    pub size: u64,
+    pub modified: Option<i64>,
+    pub target: PathBuf,
+    pub offset: u64,
+    pub sha256: String,
+}
+
+impl PartialFile {
+    fn matches(&self, entry: &FileEntry) -> bool {
+        self.relative_path == entry.relative_path
+            && self.size == entry.size
+            && self.modified == entry.modified
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResumeState {
+    pub transfer_id: String,
+    pub sender_id: String,
+    // 接受时对端的地址和清单指纹，重连时三者都一致才视为同一个传输
+    #[serde(default)]
+    pub peer_ip: String,
+    #[serde(default)]
+    pub fingerprint: String,
+    pub root: PathBuf,
+    pub collision_policy: CollisionPolicy,
+    // 清单中每一项的保存位置，与首次接受时的规划一致
+    pub targets: Vec<Option<PathBuf>>,
+    // 已经校验并改名到位的文件序号
+    pub completed: Vec<u32>,
+    pub partial: HashMap<u32, PartialFile>,
+    pub updated: DateTime<Local>,
+}
+
+impl ResumeState {
+    pub fn new(
+        transfer_id: &str,
+        sender_id: &str,
+        peer_ip: &str,
+        files: &[FileEntry],
+        root: &Path,
+        collision_policy: CollisionPolicy,
+        targets: Vec<Option<PathBuf>>,
+    ) -> Self {
+        Self {
+            transfer_id: transfer_id.to_string(),
+            sender_id: sender_id.to_string(),
+            peer_ip: peer_ip.to_string(),
+            fingerprint: fingerprint(files),
+            root: root.to_path_buf(),
+            collision_policy,
+            targets,
+            completed: Vec::new(),
+            partial: HashMap::new(),
+            updated: Local::now(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        Local::now() - self.updated > Duration::days(RESUME_EXPIRY_DAYS)
+    }
+}
+
+fn resume_file() -> Result<PathBuf, String> {
+    Ok(settings::app_data_dir()?.join(RESUME_FILE))
+}
+
+fn load_states() -> Vec<ResumeState> {
+    resume_file()
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_states(states: &[ResumeState]) -> Result<(), String> {
+    let path = resume_file()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let content = serde_json::to_string(states)
+        .map_err(|e| format!("Failed to serialize resume state: {}", e))?;
+    std::fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// 清单中决定保存内容和位置的字段的 SHA-256
+pub fn fingerprint(files: &[FileEntry]) -> String {
+    let mut hasher = Sha256::new();
+    for entry in files {
+        let fields = (
+            &entry.relative_path,
+            entry.kind,
+            entry.size,
+            &entry.sha256,
+            &entry.link_target,
+        );
+        // 每项单独序列化，字段之间不会混淆
+        hasher.update(serde_json::to_vec(&fields).unwrap_or_default());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// 同一发送方从同一地址用相同的 transfer_id 和完全相同的清单重连时，
+// 取回之前的接收状态。保存位置沿用首次接受时的规划，清单变化时必须重新询问用户
+pub fn find(
+    transfer_id: &str,
+    sender_id: &str,
+    peer_ip: &str,
+    files: &[FileEntry],
+) -> Option<ResumeState> {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let fingerprint = fingerprint(files);
+    load_states().into_iter().find(|state| {
+        state.transfer_id == transfer_id
+            && state.sender_id == sender_id
+            && state.peer_ip == peer_ip
+            && state.fingerprint == fingerprint
+            && state.targets.len() == files.len()
+    })
+}
+
+// 发送方重启后 transfer_id 会变化，按接收目录和文件指纹查找之前的临时文件
+pub fn find_partial(root: &Path, entry: &FileEntry) -> Option<PartialFile> {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    load_states()
+        .into_iter()
+        .filter(|state| state.root == root)
+        .flat_map(|state| state.partial.into_values())
+        .find(|partial| partial.matches(entry))
+}
+
+pub fn save(state: &mut ResumeState) -> Result<(), String> {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    state.updated = Local::now();
+
+    let mut states = load_states();
+    // 被新任务接管的临时文件不再属于旧记录
+    let claimed: HashSet<&PathBuf> = state.partial.values().map(|p| &p.target).collect();
+    for other in &mut states {
+        other.partial.retain(|_, p| !claimed.contains(&p.target));
+    }
+    // 没有进度的记录也保留到过期，启动时据此清理它们留下的临时文件
+    states.retain(|s| s.transfer_id != state.transfer_id);
+    states.push(state.clone());
+    save_states(&states)
+}
+
+pub fn remove(transfer_id: &str) {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut states = load_states();
+    let count = states.len();
+    states.retain(|state| state.transfer_id != transfer_id);
+    if states.len() != count {
+        if let Err(e) = save_states(&states) {
+            log::warn!("{}", e);
+        }
+    }
+}
+
+// 启动时丢弃过期的记录，返回记录的保存位置上已经不能续传的临时文件。
+// 接受传输时就保存了每一项的保存位置，中断时还没有断点的临时文件也能找到
+pub fn stale_part_files() -> Vec<PathBuf> {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let states = load_states();
+    let (expired, live): (Vec<_>, Vec<_>) =
+        states.into_iter().partition(|state| state.is_expired());
+    if !expired.is_empty() {
+        if let Err(e) = save_states(&live) {
+            log::warn!("{}", e);
+        }
+    }
+
+    let retained: HashSet<PathBuf> = live
+        .iter()
+        .flat_map(|state| state.partial.values())
+        .map(|partial| partfile::part_path(&partial.target))
+        .collect();
+    let mut stale: Vec<PathBuf> = live
+        .iter()
+        .chain(&expired)
+        .flat_map(|state| state.targets.iter().flatten())
+        .map(|target| partfile::part_path(target))
+        .filter(|path| !retained.contains(path))
+        .filter(|path| path.symlink_metadata().is_ok_and(|m| m.is_file()))
+        .collect();
+    stale.sort();
+    stale.dedup();
+    stale
+}
@@ -0,0 +1,299 @@
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+// 单个文件名和整个相对路径的最大字节数
+const MAX_COMPONENT_LEN: usize = 255;
+const MAX_PATH_LEN: usize = 4096;
+const MAX_DEPTH: usize = 64;
+
+// Windows 保留的设备名，带扩展名时同样不可用
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// 在任一平台上都不能出现在文件名中的字符
+const FORBIDDEN_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+// 校验对端发来的相对路径，返回 NFC 规范化后的各级名称
+pub fn validate_relative_path(relative_path: &str) -> Result<Vec<String>, String> {
+    let normalized: String = relative_path.nfc().collect();
+
+    if normalized.is_empty() {
+        return Err("Empty file name".to_string());
+    }
+    if normalized.len() > MAX_PATH_LEN {
+        return Err(format!("Path too long: {} bytes", normalized.len()));
+    }
+    if normalized.starts_with('/') {
+        return Err(format!("Absolute path not allowed: {}", relative_path));
+    }
+
+    let components: Vec<String> = normalized.split('/').map(|c| c.to_string()).collect();
+    if components.len() > MAX_DEPTH {
+        return Err(format!("Path too deep: {}", relative_path));
+    }
+
+    for component in &components {
+        validate_component(component)
+            .map_err(|e| format!("Invalid path {:?}: {}", relative_path, e))?;
+    }
+
+    Ok(components)
+}
+
+fn validate_component(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("illegal component {:?}", name));
+    }
+    if name.len() > MAX_COMPONENT_LEN {
+        return Err(format!("name longer than {} bytes", MAX_COMPONENT_LEN));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_control() || FORBIDDEN_CHARS.contains(c))
+    {
+        return Err(format!("forbidden character {:?}", c));
+    }
+    // Windows 会静默去掉结尾的点和空格，可能导致两个名字指向同一文件
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err("name ends with a dot or space".to_string());
+    }
+
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Err(format!("reserved name {:?}", name));
+    }
+
+    Ok(())
+}
+
+// 把对端的相对路径解析到接收目录下，确保结果不会逃出接收目录
+pub fn resolve_received_path(root: &Path, relative_path: &str) -> Result<PathBuf, String> {
+    let path = resolve_within_root(root, relative_path)?;
+
+    // 目标本身是符号链接时写入会落到链接指向的位置
+    if path
+        .symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+    {
+        return Err(format!(
+            "Refusing to write through symlink: {}",
+            path.display()
+        ));
+    }
+
+    Ok(path)
+}
+
+// 收到的链接会替换或避开同名的已有链接，不会写入它指向的位置
+pub fn resolve_received_link(root: &Path, relative_path: &str) -> Result<PathBuf, String> {
+    resolve_within_root(root, relative_path)
+}
+
+fn resolve_within_root(root: &Path, relative_path: &str) -> Result<PathBuf, String> {
+    let components = validate_relative_path(relative_path)?;
+    let path = components
+        .iter()
+        .fold(root.to_path_buf(), |path, component| path.join(component));
+
+    // 再次确认拼接结果只包含普通路径组件
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|_| format!("Path escapes receive directory: {}", relative_path))?;
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(format!("Path escapes receive directory: {}", relative_path));
+    }
+
+    ensure_within_root(root, &path)?;
+    Ok(path)
+}
+
+// 校验对端发来的符号链接内容，只允许指向链接所在的顶层文件夹之内。
+// 链接在所有文件写完后才创建，开头的 .. 只经过真实目录，可以按层数判断；
+// 进入子路径之后再出现 .. 可能经过其他指向目录的链接而逃出，一律拒绝
+pub fn validate_symlink_target(relative_path: &str, target: &str) -> Result<(), String> {
+    if target.is_empty() || target.len() > MAX_PATH_LEN {
+        return Err(format!("Invalid symlink target: {:?}", target));
+    }
+    if target.starts_with('/')
+        || target
+            .chars()
+            .any(|c| c.is_control() || c == '\\' || c == ':')
+    {
+        return Err(format!("Symlink target is not a relative path: {}", target));
+    }
+
+    // 链接所在目录相对于顶层文件夹的层数
+    let depth = relative_path.split('/').count().saturating_sub(2);
+    let mut ups = 0;
+    let mut descended = false;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if !descended => ups += 1,
+            ".." => return Err(format!("Symlink target is not normalized: {}", target)),
+            _ => descended = true,
+        }
+    }
+    if ups > depth || relative_path.split('/').count() < 2 {
+        return Err(format!("Symlink points outside the transfer: {}", target));
+    }
+
+    Ok(())
+}
+
+// 已存在的上级目录可能是指向别处的符号链接，解析后必须仍在接收目录内
+fn ensure_within_root(root: &Path, path: &Path) -> Result<(), String> {
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve {}: {}", root.display(), e))?;
+
+    let mut existing = path.parent();
+    while let Some(ancestor) = existing {
+        if ancestor.exists() {
+            let canonical = ancestor
+                .canonicalize()
+                .map_err(|e| format!("Failed to resolve {}: {}", ancestor.display(), e))?;
+            if !canonical.starts_with(&canonical_root) {
+                return Err(format!(
+                    "Path escapes receive directory: {}",
+                    path.display()
+                ));
+            }
+            return Ok(());
+        }
+        existing = ancestor.parent();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // 每个测试使用单独的临时目录
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "lantransfer-sanitize-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn rejects_parent_and_absolute_paths() {
+        assert!(validate_relative_path("../etc/passwd").is_err());
+        assert!(validate_relative_path("a/../../b").is_err());
+        assert!(validate_relative_path("a/./b").is_err());
+        assert!(validate_relative_path("/etc/passwd").is_err());
+        assert!(validate_relative_path("a//b").is_err());
+        assert!(validate_relative_path("a\\..\\b").is_err());
+        assert!(validate_relative_path("C:/Windows").is_err());
+        assert!(validate_relative_path("").is_err());
+        assert_eq!(
+            validate_relative_path("photos/2024/a.jpg").unwrap(),
+            vec!["photos", "2024", "a.jpg"]
+        );
+    }
+
+    #[test]
+    fn rejects_reserved_names_and_trailing_dot_or_space() {
+        for name in ["CON", "con", "nul.txt", "Com1.log", "LPT9", "aux .txt"] {
+            assert!(validate_relative_path(name).is_err(), "{}", name);
+        }
+        assert!(validate_relative_path("notes.").is_err());
+        assert!(validate_relative_path("notes ").is_err());
+        assert!(validate_relative_path("dir./file").is_err());
+        assert!(validate_relative_path("CONSOLE.txt").is_ok());
+        assert!(validate_relative_path("com10").is_ok());
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        // e + 组合重音符与预组合的 é 得到同一个名字
+        let decomposed = validate_relative_path("cafe\u{301}/re\u{301}sume\u{301}.txt").unwrap();
+        let composed = validate_relative_path("caf\u{e9}/r\u{e9}sum\u{e9}.txt").unwrap();
+        assert_eq!(decomposed, composed);
+        assert_eq!(decomposed, vec!["caf\u{e9}", "r\u{e9}sum\u{e9}.txt"]);
+    }
+
+    #[test]
+    fn limits_component_length_and_depth() {
+        let longest = "a".repeat(MAX_COMPONENT_LEN);
+        assert!(validate_relative_path(&longest).is_ok());
+        assert!(validate_relative_path(&format!("{}a", longest)).is_err());
+        // 按字节计算，多字节字符更早超出
+        assert!(validate_relative_path(&"\u{e9}".repeat(128)).is_err());
+
+        let deepest = vec!["d"; MAX_DEPTH].join("/");
+        assert!(validate_relative_path(&deepest).is_ok());
+        assert!(validate_relative_path(&format!("{}/d", deepest)).is_err());
+    }
+
+    #[test]
+    fn symlink_targets_stay_inside_the_transfer() {
+        assert!(validate_symlink_target("top/link", "file").is_ok());
+        assert!(validate_symlink_target("top/a/b/link", "../../file").is_ok());
+        assert!(validate_symlink_target("top/a/link", "./sub/file").is_ok());
+
+        // 开头的 .. 超过链接所在的层数
+        assert!(validate_symlink_target("top/link", "../file").is_err());
+        assert!(validate_symlink_target("top/a/b/link", "../../../file").is_err());
+        // 进入子目录后再出现 .. 可能经过其他链接
+        assert!(validate_symlink_target("top/a/link", "sub/../../file").is_err());
+        assert!(validate_symlink_target("top/a/link", "sub/../file").is_err());
+
+        assert!(validate_symlink_target("link", "file").is_err());
+        assert!(validate_symlink_target("top/link", "/etc/passwd").is_err());
+        assert!(validate_symlink_target("top/link", "C:file").is_err());
+        assert!(validate_symlink_target("top/link", "a\\b").is_err());
+        assert!(validate_symlink_target("top/link", "").is_err());
+    }
+
+    #[test]
+    fn paths_resolve_inside_the_root() {
+        let root = temp_root("inside");
+        fs::create_dir_all(root.join("existing")).unwrap();
+
+        assert!(ensure_within_root(&root, &root.join("existing/new/file")).is_ok());
+        assert!(ensure_within_root(&root, &root.join("missing/deeper/file")).is_ok());
+        assert_eq!(
+            resolve_received_path(&root, "existing/file").unwrap(),
+            root.join("existing").join("file")
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_cannot_escape_the_root() {
+        let root = temp_root("escape");
+        let outside = temp_root("escape-outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("file"), root.join("file")).unwrap();
+
+        assert!(ensure_within_root(&root, &root.join("link/file")).is_err());
+        assert!(ensure_within_root(&root, &root.join("link/sub/file")).is_err());
+        assert!(resolve_received_path(&root, "link/file").is_err());
+        // 目标本身是链接时不写入
+        assert!(resolve_received_path(&root, "file").is_err());
+        assert!(resolve_received_link(&root, "file").is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
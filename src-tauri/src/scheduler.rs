@@ -0,0 +1,462 @@
+use crate::control::{ControlState, TransferControl, TransferControls};
+use crate::device::{Device, DeviceManager};
+use crate::file_transfer::{FileTransferManager, SendError, TransferStateChanged};
+use crate::progress::ProgressSummary;
+use crate::protocol::ControlAction;
+use crate::queue::{self, QueueEntry, TransferPriority};
+use crate::settings::TransferSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
+
+// 连接失败的任务重新排队后等待的时间，每次失败加倍
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleStatus {
+    Queued,
+    Running,
+}
+
+// 提供给界面的任务列表项
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduledTransfer {
+    #[serde(flatten)]
+    pub entry: QueueEntry,
+    pub status: ScheduleStatus,
+    pub state: ControlState,
+}
+
+struct QueuedTransfer {
+    entry: QueueEntry,
+    // 排队时就注册，界面可以提前暂停或取消
+    control: TransferControl,
+    app_handle: tauri::AppHandle,
+    // 连续连接失败的次数，重启后清零
+    failures: u32,
+    // 失败后在此之前不再尝试，设备显示在线但连不上时不会反复重试
+    retry_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    // 按启动顺序排列，新任务插在同优先级任务之后
+    queue: VecDeque<QueuedTransfer>,
+    running: HashMap<String, QueueEntry>,
+}
+
+impl SchedulerState {
+    fn position(&self, transfer_id: &str) -> Option<usize> {
+        self.queue
+            .iter()
+            .position(|job| job.entry.transfer_id == transfer_id)
+    }
+
+    fn insert_by_priority(&mut self, job: QueuedTransfer) {
+        let position = self
+            .queue
+            .iter()
+            .position(|queued| queued.entry.priority < job.entry.priority)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(position, job);
+    }
+
+    // 正在发送的任务也要保存，应用退出后重新排在最前面
+    fn persist(&self) {
+        let entries: Vec<QueueEntry> = self
+            .running
+            .values()
+            .cloned()
+            .chain(self.queue.iter().map(|job| job.entry.clone()))
+            .collect();
+        if let Err(e) = queue::save(&entries) {
+            log::warn!("{}", e);
+        }
+    }
+}
+
+// 发送任务的调度器，按全局和单设备的并发上限启动排队的任务
+// 状态只在调度时短暂加锁，传输过程中不持有任何锁
+#[derive(Clone)]
+pub struct TransferScheduler {
+    manager: Arc<FileTransferManager>,
+    settings: Arc<Mutex<TransferSettings>>,
+    device_manager: Arc<Mutex<DeviceManager>>,
+    controls: TransferControls,
+    state: Arc<std::sync::Mutex<SchedulerState>>,
+    wakeup: Arc<Notify>,
+}
+
+impl TransferScheduler {
+    pub fn new(
+        manager: Arc<FileTransferManager>,
+        settings: Arc<Mutex<TransferSettings>>,
+        device_manager: Arc<Mutex<DeviceManager>>,
+    ) -> Self {
+        let scheduler = Self {
+            controls: manager.controls(),
+            manager,
+            settings,
+            device_manager,
+            state: Arc::new(std::sync::Mutex::new(SchedulerState::default())),
+            wakeup: Arc::new(Notify::new()),
+        };
+
+        // 新任务入队、任务结束、排队中的任务继续或有设备上线时重新调度
+        let dispatcher = scheduler.clone();
+        tokio::spawn(async move {
+            let devices_changed = dispatcher.device_manager.lock().await.changes();
+            loop {
+                tokio::select! {
+                    _ = dispatcher.wakeup.notified() => {}
+                    _ = devices_changed.notified() => {}
+                }
+                dispatcher.dispatch().await;
+            }
+        });
+
+        scheduler
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, SchedulerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // 恢复上次退出时未完成的任务，对应设备上线后自动开始
+    pub async fn restore(&self, app_handle: tauri::AppHandle) {
+        let entries = queue::load();
+        if entries.is_empty() {
+            return;
+        }
+        log::info!("Restoring {} queued transfers", entries.len());
+
+        let mut jobs = Vec::new();
+        for entry in entries {
+            let control = self.controls.register(&entry.transfer_id).await;
+            jobs.push(QueuedTransfer {
+                entry,
+                control,
+                app_handle: app_handle.clone(),
+                failures: 0,
+                retry_at: None,
+            });
+        }
+
+        let mut state = self.lock_state();
+        state.queue.extend(jobs);
+        state.persist();
+        drop(state);
+        self.wakeup.notify_one();
+    }
+
+    pub async fn enqueue(
+        &self,
+        peer: Device,
+        file_paths: Vec<String>,
+        priority: TransferPriority,
+        app_handle: tauri::AppHandle,
+    ) -> String {
+        let entry = QueueEntry::new(peer, file_paths, priority);
+        let transfer_id = entry.transfer_id.clone();
+        let control = self.controls.register(&transfer_id).await;
+
+        let job = QueuedTransfer {
+            entry,
+            control,
+            app_handle: app_handle.clone(),
+            failures: 0,
+            retry_at: None,
+        };
+        let _ = app_handle.emit("transfer-queued", &Self::queued_entry(&job));
+        let mut state = self.lock_state();
+        state.insert_by_priority(job);
+        state.persist();
+        drop(state);
+        self.wakeup.notify_one();
+
+        transfer_id
+    }
+
+    // 排队中和正在进行的全部任务
+    pub async fn list(&self) -> Vec<ScheduledTransfer> {
+        let (mut transfers, running) = {
+            let state = self.lock_state();
+            let queued: Vec<_> = state.queue.iter().map(Self::queued_entry).collect();
+            let running: Vec<_> = state.running.values().cloned().collect();
+            (queued, running)
+        };
+
+        for entry in running {
+            let state = self
+                .controls
+                .state(&entry.transfer_id)
+                .await
+                .unwrap_or(ControlState::Running);
+            transfers.push(ScheduledTransfer {
+                entry,
+                status: ScheduleStatus::Running,
+                state,
+            });
+        }
+        transfers
+    }
+
+    // 等待启动的任务，按启动顺序排列
+    pub fn list_queue(&self) -> Vec<ScheduledTransfer> {
+        self.lock_state()
+            .queue
+            .iter()
+            .map(Self::queued_entry)
+            .collect()
+    }
+
+    // 移动排队中的任务，未指定位置时按新的优先级重新插入
+    pub fn reorder(
+        &self,
+        transfer_id: &str,
+        position: Option<usize>,
+        priority: Option<TransferPriority>,
+    ) -> Result<(), String> {
+        let mut state = self.lock_state();
+        let current = state
+            .position(transfer_id)
+            .ok_or_else(|| format!("Transfer not in queue: {}", transfer_id))?;
+        let mut job = state.queue.remove(current).unwrap();
+        if let Some(priority) = priority {
+            job.entry.priority = priority;
+        }
+
+        match position {
+            Some(position) => {
+                let position = position.min(state.queue.len());
+                state.queue.insert(position, job);
+            }
+            None => state.insert_by_priority(job),
+        }
+        state.persist();
+        drop(state);
+
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    pub async fn remove(&self, transfer_id: &str) -> Result<(), String> {
+        let job = {
+            let mut state = self.lock_state();
+            let position = state
+                .position(transfer_id)
+                .ok_or_else(|| format!("Transfer not in queue: {}", transfer_id))?;
+            let job = state.queue.remove(position).unwrap();
+            state.persist();
+            job
+        };
+
+        self.release(transfer_id).await;
+        FileTransferManager::emit_finished(
+            &job.app_handle,
+            transfer_id,
+            "cancelled",
+            ProgressSummary::default(),
+        );
+        Ok(())
+    }
+
+    // 暂停、继续或取消，排队中的任务被取消时直接移出队列
+    pub async fn control(&self, transfer_id: &str, action: ControlAction) -> Result<(), String> {
+        self.controls.apply(transfer_id, action).await?;
+
+        // 正在运行的任务由传输任务自己通知界面，排队中的任务在这里通知
+        let queued = {
+            let state = self.lock_state();
+            state
+                .position(transfer_id)
+                .map(|position| &state.queue[position])
+                .map(|job| (job.app_handle.clone(), job.control.state()))
+        };
+        if let Some((app_handle, control_state)) = queued {
+            if action == ControlAction::Cancel {
+                return self.remove(transfer_id).await;
+            }
+            let changed = TransferStateChanged {
+                transfer_id: transfer_id.to_string(),
+                state: control_state,
+            };
+            let _ = app_handle.emit("transfer-state", &changed);
+        }
+
+        // 暂停的排队任务不占用名额，继续后可能可以启动
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    // 任务彻底移出队列时注销控制通道，并清除它单独的限速
+    async fn release(&self, transfer_id: &str) {
+        self.controls.unregister(transfer_id).await;
+        self.manager.limiter().finish(transfer_id);
+    }
+
+    fn queued_entry(job: &QueuedTransfer) -> ScheduledTransfer {
+        ScheduledTransfer {
+            entry: job.entry.clone(),
+            status: ScheduleStatus::Queued,
+            state: job.control.state(),
+        }
+    }
+
+    async fn dispatch(&self) {
+        let (max_total, max_per_peer) = {
+            let settings = self.settings.lock().await;
+            (
+                settings.max_concurrent_transfers.max(1),
+                settings.max_transfers_per_peer.max(1),
+            )
+        };
+        // 只启动目标设备在线的任务
+        let online: Vec<Device> = {
+            let device_manager = self.device_manager.lock().await;
+            let current_id = device_manager.get_current_device().id.clone();
+            device_manager
+                .get_devices()
+                .into_iter()
+                .filter(|device| device.id != current_id && device.is_online)
+                .collect()
+        };
+
+        let ready = {
+            let mut state = self.lock_state();
+            let mut per_peer: HashMap<String, usize> = HashMap::new();
+            for entry in state.running.values() {
+                *per_peer.entry(entry.peer.id.clone()).or_default() += 1;
+            }
+
+            let now = Instant::now();
+            let mut ready = Vec::new();
+            let mut waiting = VecDeque::new();
+            while let Some(mut job) = state.queue.pop_front() {
+                let device = online.iter().find(|device| job.entry.matches(device));
+                let startable = match device {
+                    Some(device) => {
+                        let peer_count = per_peer.get(&device.id).copied().unwrap_or(0);
+                        state.running.len() < max_total
+                            && peer_count < max_per_peer
+                            && job.control.state() == ControlState::Running
+                            && job.retry_at.is_none_or(|retry_at| retry_at <= now)
+                    }
+                    None => false,
+                };
+
+                if let (true, Some(device)) = (startable, device) {
+                    // 对端重启后地址可能变化
+                    job.entry.peer = device.clone();
+                    *per_peer.entry(device.id.clone()).or_default() += 1;
+                    state
+                        .running
+                        .insert(job.entry.transfer_id.clone(), job.entry.clone());
+                    ready.push(job);
+                } else {
+                    waiting.push_back(job);
+                }
+            }
+            state.queue = waiting;
+            if !ready.is_empty() {
+                state.persist();
+            }
+            ready
+        };
+
+        for job in ready {
+            self.start(job);
+        }
+    }
+
+    fn start(&self, job: QueuedTransfer) {
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let QueuedTransfer {
+                entry,
+                mut control,
+                app_handle,
+                failures,
+                ..
+            } = job;
+            let transfer_id = entry.transfer_id.clone();
+            let result = scheduler
+                .manager
+                .send_files(
+                    entry.peer.clone(),
+                    entry.file_paths.clone(),
+                    &mut control,
+                    app_handle.clone(),
+                )
+                .await;
+
+            match result {
+                Ok(()) => scheduler.release(&transfer_id).await,
+                // 连接失败或中断的任务按原优先级重新排队，被拒绝或取消的任务才移出队列。
+                // 控制通道保持注册，等待重试期间仍然可以暂停或取消
+                Err(SendError::Interrupted(e)) => {
+                    let delay = RETRY_DELAY
+                        .saturating_mul(2u32.saturating_pow(failures))
+                        .min(MAX_RETRY_DELAY);
+                    let job = QueuedTransfer {
+                        entry,
+                        control,
+                        app_handle: app_handle.clone(),
+                        failures: failures + 1,
+                        retry_at: Some(Instant::now() + delay),
+                    };
+                    // 与 control 中查找排队任务互斥，中断后到重新排队之前的取消不会被漏掉
+                    let requeued = {
+                        let mut state = scheduler.lock_state();
+                        if job.control.state() == ControlState::Cancelled {
+                            false
+                        } else {
+                            let _ = app_handle.emit("transfer-queued", &Self::queued_entry(&job));
+                            state.insert_by_priority(job);
+                            true
+                        }
+                    };
+
+                    if requeued {
+                        log::warn!(
+                            "Transfer {} interrupted ({}), retrying in {:?}",
+                            transfer_id,
+                            e,
+                            delay
+                        );
+                        let retry = scheduler.clone();
+                        tokio::spawn(async move {
+                            time::sleep(delay).await;
+                            retry.wakeup.notify_one();
+                        });
+                    } else {
+                        scheduler.release(&transfer_id).await;
+                        FileTransferManager::emit_finished(
+                            &app_handle,
+                            &transfer_id,
+                            "cancelled",
+                            ProgressSummary::default(),
+                        );
+                    }
+                }
+                Err(SendError::Failed(e)) => {
+                    scheduler.release(&transfer_id).await;
+                    log::error!("Failed to send files: {}", e);
+                }
+            }
+
+            {
+                let mut state = scheduler.lock_state();
+                state.running.remove(&transfer_id);
+                state.persist();
+            }
+            scheduler.wakeup.notify_one();
+        });
+    }
+}
@@ -0,0 +1,140 @@
+use crate::protocol::SessionParams;
+use tokio::fs;
+use tokio::net::tcp::OwnedWriteHalf;
+
+// 用 sendfile 发送数据块: 文件内容由内核从页缓存直接发到套接字，不复制成帧。
+// 不需要压缩的文件连读都不读，发送方不逐块计算校验和，接收方用清单中的整体 SHA-256 核对；
+// 需要尝试压缩的文件仍要读出数据，其中不压缩的数据块同样由内核发送
+//
+// 只有明文会话可以把文件内容原样交给内核发送
+pub fn supported(session: &SessionParams) -> bool {
+    cfg!(target_os = "linux") && session.encryption == "none"
+}
+
+// 发送帧头后由内核把文件中 offset 开始的 len 字节从页缓存发到套接字，
+// 文件所在的文件系统不支持时读出这段内容再写入
+pub async fn send_data(
+    writer: &mut OwnedWriteHalf,
+    header: &[u8],
+    file: &fs::File,
+    offset: u64,
+    len: usize,
+) -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::send_data(writer, header, file, offset, len).await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (writer, header, file, offset, len);
+        Err("sendfile is not supported on this platform".to_string())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::FileExt;
+    use tokio::fs;
+    use tokio::io::{AsyncWriteExt, Interest};
+    use tokio::net::tcp::OwnedWriteHalf;
+    use tokio::net::TcpStream;
+
+    // 套接字是非阻塞的，写满时等待可写再重试
+    async fn write_with(
+        stream: &TcpStream,
+        mut op: impl FnMut() -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        loop {
+            stream.writable().await?;
+            match stream.try_io(Interest::WRITABLE, &mut op) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn check(result: isize) -> io::Result<usize> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    pub async fn send_data(
+        writer: &mut OwnedWriteHalf,
+        header: &[u8],
+        file: &fs::File,
+        offset: u64,
+        len: usize,
+    ) -> Result<(), String> {
+        let stream: &TcpStream = writer.as_ref();
+        let socket = stream.as_raw_fd();
+
+        // MSG_MORE 让帧头和随后的数据合并成完整的报文段，效果与合并写入相同
+        let mut sent = 0;
+        while sent < header.len() {
+            let rest = &header[sent..];
+            sent += write_with(stream, || {
+                check(unsafe {
+                    libc::send(
+                        socket,
+                        rest.as_ptr().cast(),
+                        rest.len(),
+                        libc::MSG_MORE | libc::MSG_NOSIGNAL,
+                    )
+                })
+            })
+            .await
+            .map_err(|e| format!("Failed to send Data frame: {}", e))?;
+        }
+
+        let source = file.as_raw_fd();
+        let mut position = offset as libc::off_t;
+        let mut sent = 0;
+        while sent < len {
+            let count = len - sent;
+            let result = write_with(stream, || {
+                check(unsafe { libc::sendfile(socket, source, &mut position, count) })
+            })
+            .await;
+            match result {
+                Ok(0) => return Err("File changed during transfer".to_string()),
+                Ok(n) => sent += n,
+                // 文件所在的文件系统不支持 sendfile 时读出剩余内容再写入
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+                    ) =>
+                {
+                    let rest = read_range(file, position as u64, count).await?;
+                    return writer
+                        .write_all(&rest)
+                        .await
+                        .map_err(|e| format!("Failed to send Data frame: {}", e));
+                }
+                Err(e) => return Err(format!("Failed to send Data frame: {}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_range(file: &fs::File, offset: u64, len: usize) -> Result<Vec<u8>, String> {
+        let file = file
+            .try_clone()
+            .await
+            .map_err(|e| format!("Failed to read file: {}", e))?
+            .into_std()
+            .await;
+        tokio::task::spawn_blocking(move || {
+            let mut buffer = vec![0; len];
+            file.read_exact_at(&mut buffer, offset).map(|()| buffer)
+        })
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?
+        .map_err(|e| format!("Failed to read file: {}", e))
+    }
+}
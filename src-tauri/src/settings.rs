@@ -0,0 +1,109 @@
+use crate::collision::CollisionPolicy;
+use crate::ratelimit::QuietHours;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// 与 tauri.conf.json 中的 identifier 保持一致
+const APP_IDENTIFIER: &str = "com.lantransfer.app";
+const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferSettings {
+    // 发送文件夹时跳过的文件名，支持 * 和 ? 通配符
+    pub ignore_patterns: Vec<String>,
+    // 发送文件夹时跟随其中指向文件的符号链接发送目标内容，关闭时按链接本身发送
+    pub follow_symlinks: bool,
+    // 等待用户确认接收的秒数，超时自动拒绝
+    pub accept_timeout_secs: u64,
+    // 接收的文件与已有文件重名时的默认处理方式
+    pub collision_policy: CollisionPolicy,
+    // 同时进行的发送任务总数，超出的任务排队等待
+    pub max_concurrent_transfers: usize,
+    // 对同一台设备同时进行的发送任务数
+    pub max_transfers_per_peer: usize,
+    // 大文件并行发送使用的连接数，为 1 时不拆分
+    pub parallel_streams: usize,
+    // 套接字发送和接收缓冲区的字节数，为 0 时由系统自动调整
+    pub socket_send_buffer: usize,
+    pub socket_recv_buffer: usize,
+    // 关闭 Nagle 算法，控制帧和每个文件的结尾帧不会被延迟
+    pub tcp_nodelay: bool,
+    // 发送的总限速，字节/秒，为 0 时不限速
+    pub bandwidth_limit: u64,
+    // 对单台设备的发送限速，按对端保存在其数据目录中的设备 ID，重启后仍然有效
+    pub device_bandwidth_limits: HashMap<String, u64>,
+    // 在这段时间内自动使用更低的总限速
+    pub quiet_hours: QuietHours,
+    // 接收的文件恢复发送方的修改时间和权限位
+    pub preserve_metadata: bool,
+    // 发送时附带扩展属性，接收时在 preserve_metadata 开启时恢复
+    pub preserve_xattrs: bool,
+}
+
+impl Default for TransferSettings {
+    fn default() -> Self {
+        Self {
+            ignore_patterns: vec![
+                ".git".to_string(),
+                "node_modules".to_string(),
+                ".DS_Store".to_string(),
+                "Thumbs.db".to_string(),
+            ],
+            follow_symlinks: false,
+            accept_timeout_secs: 60,
+            collision_policy: CollisionPolicy::Rename,
+            max_concurrent_transfers: 3,
+            max_transfers_per_peer: 1,
+            parallel_streams: 4,
+            socket_send_buffer: 0,
+            socket_recv_buffer: 0,
+            tcp_nodelay: true,
+            bandwidth_limit: 0,
+            device_bandwidth_limits: HashMap::new(),
+            quiet_hours: QuietHours::default(),
+            preserve_metadata: true,
+            preserve_xattrs: false,
+        }
+    }
+}
+
+impl TransferSettings {
+    // 读取失败时使用默认设置，不影响应用启动
+    pub fn load() -> Self {
+        let path = match app_data_dir() {
+            Ok(dir) => dir.join(SETTINGS_FILE),
+            Err(e) => {
+                log::warn!("{}", e);
+                return Self::default();
+            }
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::warn!("Failed to parse {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let dir = app_data_dir()?;
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+        std::fs::write(dir.join(SETTINGS_FILE), content)
+            .map_err(|e| format!("Failed to save settings: {}", e))
+    }
+}
+
+// 应用数据目录，与 Tauri 的 app_data_dir 相同，但在创建 AppHandle 之前也能使用
+pub fn app_data_dir() -> Result<PathBuf, String> {
+    dirs::data_dir()
+        .map(|dir| dir.join(APP_IDENTIFIER))
+        .ok_or_else(|| "Failed to get app data directory".to_string())
+}
@@ -0,0 +1,72 @@
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+// 计算校验和时代替空洞内容的全零缓冲区
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+// 稀疏文件中有数据的区间 [start, end)，按偏移排序。
+// 文件没有空洞或系统无法查询时返回 None，调用方按普通文件完整发送
+pub fn data_extents(path: &Path, len: u64) -> Option<Vec<(u64, u64)>> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::data_extents(path, len)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, len);
+        None
+    }
+}
+
+// 空洞按全零计入校验和，双方对完整内容的校验和保持一致
+pub fn hash_zeros(hasher: &mut Sha256, mut len: u64) {
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    pub fn data_extents(path: &Path, len: u64) -> Option<Vec<(u64, u64)>> {
+        let file = File::open(path).ok()?;
+        let metadata = file.metadata().ok()?;
+        // 实际占用的块不少于文件长度时没有空洞
+        if metadata.blocks() * 512 >= len {
+            return None;
+        }
+
+        let fd = file.as_raw_fd();
+        let mut extents = Vec::new();
+        let mut offset = 0u64;
+        while offset < len {
+            let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+            if start < 0 {
+                // 之后全是空洞
+                if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                    break;
+                }
+                return None;
+            }
+            let start = start as u64;
+            if start >= len {
+                break;
+            }
+            let end = unsafe { libc::lseek(fd, start as libc::off_t, libc::SEEK_HOLE) };
+            if end < 0 {
+                return None;
+            }
+            let end = (end as u64).min(len);
+            extents.push((start, end));
+            offset = end;
+        }
+        Some(extents)
+    }
+}
@@ -0,0 +1,420 @@
+use crate::compression::{Codec, Compressor};
+use crate::control::ControlState;
+use crate::partfile;
+use crate::protocol::{self, ByteRange, Connection, Frame, FrameType, JoinMessage, SessionParams};
+use crate::ratelimit::Throttle;
+use crate::sendfile;
+use crate::tuning::SocketOptions;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time;
+
+// 小于该大小的文件不拆分，额外连接和分段校验的开销不划算
+pub const STRIPE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+// 拆分时每个数据帧的大小
+const STRIPE_CHUNK_SIZE: usize = 256 * 1024;
+// 区间边界按该大小对齐
+const RANGE_ALIGN: u64 = 1024 * 1024;
+// 接收方等待数据连接加入的时间
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 并行任务的结果: 用完的数据连接和该区间的 SHA-256，按整个文件核对时为空
+pub type StripeResult = Result<(Connection, String), String>;
+
+// 一个区间已发送或写入的字节数，以及这些数据在连接上实际占用的字节数
+#[derive(Default)]
+pub struct RangeProgress {
+    pub done: AtomicU64,
+    pub wire: AtomicU64,
+}
+
+impl RangeProgress {
+    fn add(&self, bytes: usize, wire: usize) {
+        self.done.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+}
+
+// 把 [start, size) 平均分成 streams 段，最后一段包含余数
+pub fn split_ranges(start: u64, size: u64, streams: usize) -> Vec<ByteRange> {
+    let total = size.saturating_sub(start);
+    let streams = (streams as u64).clamp(1, (total / RANGE_ALIGN).max(1));
+    let step = (total / streams / RANGE_ALIGN).max(1) * RANGE_ALIGN;
+
+    let mut ranges = Vec::new();
+    let mut offset = start;
+    for i in 0..streams {
+        let len = if i + 1 == streams {
+            size - offset
+        } else {
+            step
+        };
+        ranges.push(ByteRange { offset, len });
+        offset += len;
+    }
+    ranges
+}
+
+// 一次传输的并行数据连接，发送方主动建立，接收方等待对端加入
+#[derive(Default)]
+pub struct Stripes {
+    conns: Vec<Option<Connection>>,
+    incoming: Option<mpsc::Receiver<(u32, Connection)>>,
+    // 协商允许的连接数上限
+    limit: usize,
+    options: SendOptions,
+}
+
+// 发送一个区间时每个数据帧的大小、是否用 sendfile 发送，以及协商的压缩算法。
+// whole_file 时不读出内容也不计算区间校验和，由接收方按整个文件核对
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions {
+    pub chunk_size: usize,
+    pub sendfile: bool,
+    pub codec: Option<Codec>,
+    pub whole_file: bool,
+}
+
+impl Stripes {
+    // 发送方: 建立 count 个数据连接，任何一个失败都放弃拆分
+    pub async fn connect(
+        addr: &str,
+        transfer_id: &str,
+        count: usize,
+        session: &SessionParams,
+        socket_options: &SocketOptions,
+    ) -> Result<Self, String> {
+        let mut conns = Vec::with_capacity(count);
+        for stream_index in 0..count {
+            let mut stream = TcpStream::connect(addr)
+                .await
+                .map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+            socket_options.apply(&stream);
+            protocol::write_preamble(&mut stream).await?;
+            protocol::read_preamble(&mut stream).await?;
+
+            let join = JoinMessage {
+                transfer_id: transfer_id.to_string(),
+                stream_index: stream_index as u32,
+            };
+            protocol::write_frame(&mut stream, &Frame::json(FrameType::Join, &join)?).await?;
+            conns.push(Some(Connection::new(stream)));
+        }
+
+        Ok(Self {
+            conns,
+            incoming: None,
+            limit: count,
+            options: SendOptions {
+                chunk_size: STRIPE_CHUNK_SIZE.min(session.chunk_size as usize),
+                sendfile: sendfile::supported(session),
+                codec: Codec::from_session(session),
+                whole_file: false,
+            },
+        })
+    }
+
+    // 发送方可用的数据连接数，不超过 1 时按顺序发送
+    pub fn available(&self) -> usize {
+        self.conns.iter().filter(|conn| conn.is_some()).count()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // 并行连接与控制连接共用同一个传输的限速
+    pub fn set_throttle(&mut self, throttle: &Throttle) {
+        for conn in self.conns.iter_mut().flatten() {
+            conn.set_throttle(throttle.clone());
+        }
+    }
+
+    pub fn send_options(&self) -> SendOptions {
+        self.options
+    }
+
+    // 取出前 count 个数据连接，接收方会等待尚未加入的连接
+    pub async fn take(&mut self, count: usize) -> Result<Vec<Connection>, String> {
+        if self.conns.len() < count {
+            self.conns.resize_with(count, || None);
+        }
+
+        let deadline = time::Instant::now() + JOIN_TIMEOUT;
+        while self.conns[..count].iter().any(|conn| conn.is_none()) {
+            let incoming = self
+                .incoming
+                .as_mut()
+                .ok_or("Not enough parallel connections")?;
+            let (stream_index, conn) = time::timeout_at(deadline, incoming.recv())
+                .await
+                .map_err(|_| "Timed out waiting for parallel connections".to_string())?
+                .ok_or("Parallel connections closed")?;
+            let slot = self
+                .conns
+                .get_mut(stream_index as usize)
+                .ok_or_else(|| format!("Unexpected stream index: {}", stream_index))?;
+            *slot = Some(conn);
+        }
+
+        Ok(self.conns[..count]
+            .iter_mut()
+            .filter_map(|conn| conn.take())
+            .collect())
+    }
+
+    // 一个文件发送完后放回，供下一个文件使用
+    pub fn restore(&mut self, conns: Vec<Connection>) {
+        for (slot, conn) in self.conns.iter_mut().zip(conns) {
+            *slot = Some(conn);
+        }
+    }
+}
+
+type PendingStripes = HashMap<String, (String, usize, mpsc::Sender<(u32, Connection)>)>;
+
+// 接收方正在等待数据连接的传输
+#[derive(Clone, Default)]
+pub struct StripeRegistry {
+    transfers: Arc<Mutex<PendingStripes>>,
+}
+
+impl StripeRegistry {
+    pub async fn register(&self, transfer_id: &str, peer_ip: &str, streams: usize) -> Stripes {
+        let (conn_tx, incoming) = mpsc::channel(streams.max(1));
+        self.transfers.lock().await.insert(
+            transfer_id.to_string(),
+            (peer_ip.to_string(), streams, conn_tx),
+        );
+
+        Stripes {
+            conns: Vec::new(),
+            incoming: Some(incoming),
+            limit: streams,
+            options: SendOptions::default(),
+        }
+    }
+
+    pub async fn unregister(&self, transfer_id: &str) {
+        self.transfers.lock().await.remove(transfer_id);
+    }
+
+    // 只接受与控制连接来自同一地址的数据连接
+    pub async fn join(
+        &self,
+        join: JoinMessage,
+        peer_ip: &str,
+        stream: TcpStream,
+    ) -> Result<(), String> {
+        let conn_tx = {
+            let transfers = self.transfers.lock().await;
+            let (expected_ip, streams, conn_tx) = transfers
+                .get(&join.transfer_id)
+                .ok_or_else(|| format!("Transfer not found: {}", join.transfer_id))?;
+            if expected_ip != peer_ip || join.stream_index as usize >= *streams {
+                return Err(format!(
+                    "Rejected parallel connection {} from {}",
+                    join.stream_index, peer_ip
+                ));
+            }
+            conn_tx.clone()
+        };
+
+        conn_tx
+            .send((join.stream_index, Connection::new(stream)))
+            .await
+            .map_err(|_| "Transfer already finished".to_string())
+    }
+}
+
+// 发送一个区间，暂停时在数据块之间等待
+pub async fn send_range(
+    mut conn: Connection,
+    file_index: u32,
+    source: PathBuf,
+    range: ByteRange,
+    options: SendOptions,
+    mut state: watch::Receiver<ControlState>,
+    sent: Arc<RangeProgress>,
+) -> StripeResult {
+    let mut file = fs::File::open(&source)
+        .await
+        .map_err(|e| format!("Failed to open file: {}", e))?;
+    file.seek(SeekFrom::Start(range.offset))
+        .await
+        .map_err(|e| format!("Failed to seek file: {}", e))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = if options.whole_file {
+        Vec::new()
+    } else {
+        vec![0; options.chunk_size]
+    };
+    let mut compressor = options.codec.map(|codec| Compressor::new(codec, &source));
+    let end = range.offset + range.len;
+    let mut offset = range.offset;
+    while offset < end {
+        loop {
+            let current = *state.borrow_and_update();
+            match current {
+                ControlState::Running => break,
+                ControlState::Cancelled => return Err("Transfer cancelled".to_string()),
+                ControlState::Paused => state
+                    .changed()
+                    .await
+                    .map_err(|_| "Transfer cancelled".to_string())?,
+            }
+        }
+
+        let to_read = (end - offset).min(options.chunk_size as u64) as usize;
+        let (bytes_read, wire) = if options.whole_file {
+            let wire = conn
+                .send_file_range(file_index, offset, &file, to_read)
+                .await?;
+            (to_read, wire)
+        } else {
+            let bytes_read = file
+                .read(&mut buffer[..to_read])
+                .await
+                .map_err(|e| format!("Failed to read file: {}", e))?;
+            if bytes_read == 0 {
+                return Err(format!(
+                    "File changed during transfer: {}",
+                    source.display()
+                ));
+            }
+
+            hasher.update(&buffer[..bytes_read]);
+            let wire = conn
+                .send_file_data(
+                    file_index,
+                    offset,
+                    &file,
+                    &buffer[..bytes_read],
+                    options.sendfile,
+                    compressor.as_mut(),
+                )
+                .await?;
+            (bytes_read, wire)
+        };
+        offset += bytes_read as u64;
+        sent.add(bytes_read, wire);
+    }
+
+    if options.whole_file {
+        return Ok((conn, String::new()));
+    }
+    Ok((conn, format!("{:x}", hasher.finalize())))
+}
+
+// 把一个区间按偏移写到临时文件的对应位置，written 为该区间已写入的字节数。
+// 发送方按整个文件核对时 checksum 为 false，不计算该区间的校验和
+pub async fn receive_range(
+    mut conn: Connection,
+    file_index: u32,
+    file: Arc<std::fs::File>,
+    range: ByteRange,
+    codec: Option<Codec>,
+    checksum: bool,
+    written: Arc<RangeProgress>,
+) -> StripeResult {
+    let mut hasher = checksum.then(Sha256::new);
+    let end = range.offset + range.len;
+    let mut offset = range.offset;
+    while offset < end {
+        let (frame, wire) = conn.recv().await?.decompress(codec)?;
+        let frame = frame.expect(FrameType::Data)?;
+        let chunk = frame.parse_data()?;
+        if chunk.file_index != file_index || chunk.offset != offset {
+            return Err(format!(
+                "Unexpected data for file {} at offset {}, expected file {} at offset {}",
+                chunk.file_index, chunk.offset, file_index, offset
+            ));
+        }
+        if offset + chunk.bytes.len() as u64 > end {
+            return Err("Received more data than announced".to_string());
+        }
+
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(chunk.bytes);
+        }
+        let len = chunk.bytes.len();
+        // 数据帧整个交给阻塞线程写入，不再复制一份
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || {
+            let chunk = frame.parse_data()?;
+            partfile::write_at(&file, chunk.bytes, offset)
+                .map_err(|e| format!("Failed to write to file: {}", e))
+        })
+        .await
+        .map_err(|e| format!("Failed to write to file: {}", e))??;
+        offset += len as u64;
+        written.add(len, wire);
+    }
+
+    let checksum = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+    Ok((conn, checksum.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 区间首尾相接地覆盖 [start, size)，除最后一段外长度按 RANGE_ALIGN 对齐
+    fn assert_covers(ranges: &[ByteRange], start: u64, size: u64) {
+        assert_eq!(ranges.first().unwrap().offset, start);
+        let last = ranges.last().unwrap();
+        assert_eq!(last.offset + last.len, size);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+        }
+        for range in &ranges[..ranges.len() - 1] {
+            assert!(range.len > 0);
+            assert_eq!(range.len % RANGE_ALIGN, 0);
+        }
+    }
+
+    #[test]
+    fn splits_into_aligned_ranges_without_gaps() {
+        let size = 100 * RANGE_ALIGN + 12345;
+        let ranges = split_ranges(0, size, 4);
+        assert_eq!(ranges.len(), 4);
+        assert_covers(&ranges, 0, size);
+        // 余数都放在最后一段
+        assert_eq!(ranges[0].len, 25 * RANGE_ALIGN);
+        assert_eq!(ranges[3].len, 25 * RANGE_ALIGN + 12345);
+    }
+
+    #[test]
+    fn splits_from_a_resume_offset() {
+        let start = 3 * RANGE_ALIGN + 17;
+        let size = 67 * RANGE_ALIGN;
+        let ranges = split_ranges(start, size, 3);
+        assert_eq!(ranges.len(), 3);
+        assert_covers(&ranges, start, size);
+    }
+
+    #[test]
+    fn small_files_use_fewer_ranges() {
+        let ranges = split_ranges(0, 2 * RANGE_ALIGN + 1, 8);
+        assert_eq!(ranges.len(), 2);
+        assert_covers(&ranges, 0, 2 * RANGE_ALIGN + 1);
+
+        let ranges = split_ranges(0, RANGE_ALIGN / 2, 8);
+        assert_eq!(ranges.len(), 1);
+        assert_covers(&ranges, 0, RANGE_ALIGN / 2);
+
+        let ranges = split_ranges(0, 10 * RANGE_ALIGN, 0);
+        assert_eq!(ranges.len(), 1);
+        assert_covers(&ranges, 0, 10 * RANGE_ALIGN);
+    }
+}
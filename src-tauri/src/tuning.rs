@@ -0,0 +1,313 @@
+use crate::settings::TransferSettings;
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::net::{TcpListener, TcpStream};
+
+// 块大小的范围和初始值，上限还受协商的 chunk_size 限制
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+const INITIAL_CHUNK_SIZE: usize = 256 * 1024;
+// 每个数据帧大约占用的传输时间: 太小时系统调用过多，太大时暂停和进度不够及时
+const FRAME_TIME: Duration = Duration::from_millis(5);
+// 接收方写入磁盘的缓冲区范围，大约缓存 FLUSH_TIME 的数据再写入
+const MIN_WRITE_BUFFER: usize = 256 * 1024;
+const INITIAL_WRITE_BUFFER: usize = 1024 * 1024;
+const MAX_WRITE_BUFFER: usize = 8 * 1024 * 1024;
+const FLUSH_TIME: Duration = Duration::from_millis(50);
+// 吞吐量的采样周期，至少覆盖几个往返时间
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+const SAMPLE_RTTS: u32 = 4;
+// 采样周期远超预期时多半是暂停过，丢弃这次采样
+const STALE_SAMPLES: u32 = 5;
+// 单条连接和拆分发送各自吞吐量的平滑系数
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+
+// 套接字选项，缓冲区为 0 时交给系统自动调整
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    pub send_buffer: usize,
+    pub recv_buffer: usize,
+    pub nodelay: bool,
+}
+
+impl SocketOptions {
+    pub fn from_settings(settings: &TransferSettings) -> Self {
+        Self {
+            send_buffer: settings.socket_send_buffer,
+            recv_buffer: settings.socket_recv_buffer,
+            nodelay: settings.tcp_nodelay,
+        }
+    }
+
+    // 设置失败不影响传输，只是性能可能不理想
+    pub fn apply(&self, stream: &TcpStream) {
+        if let Err(e) = stream.set_nodelay(self.nodelay) {
+            log::warn!("Failed to set TCP_NODELAY: {}", e);
+        }
+        let socket = SockRef::from(stream);
+        if self.send_buffer > 0 {
+            if let Err(e) = socket.set_send_buffer_size(self.send_buffer) {
+                log::warn!("Failed to set socket send buffer: {}", e);
+            }
+        }
+        if self.recv_buffer > 0 {
+            if let Err(e) = socket.set_recv_buffer_size(self.recv_buffer) {
+                log::warn!("Failed to set socket receive buffer: {}", e);
+            }
+        }
+    }
+
+    // 接收缓冲区要在建立连接之前设置才能协商足够大的 TCP 窗口，接受的连接会继承
+    pub fn apply_listener(&self, listener: &TcpListener) {
+        if self.recv_buffer > 0 {
+            if let Err(e) = SockRef::from(listener).set_recv_buffer_size(self.recv_buffer) {
+                log::warn!("Failed to set socket receive buffer: {}", e);
+            }
+        }
+    }
+}
+
+// 一条连接当前的参数和测量值，用于排查链路慢的原因
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkDiagnostics {
+    pub transfer_id: String,
+    // "send" 或 "receive"
+    pub direction: String,
+    pub peer: String,
+    pub rtt_ms: f64,
+    // 最近一个采样周期的字节/秒
+    pub throughput: f64,
+    // 带宽时延积，套接字缓冲区比它小很多时链路跑不满
+    pub bdp_bytes: u64,
+    // 发送方选择的块大小，接收方为协商的上限
+    pub chunk_size: usize,
+    // 接收方写入磁盘的缓冲区，发送方为 0
+    pub write_buffer: usize,
+    // 系统实际使用的套接字缓冲区大小
+    pub send_buffer: usize,
+    pub recv_buffer: usize,
+    pub nodelay: bool,
+    pub streams: usize,
+    // 单条连接和拆分到多个连接时各自测得的字节/秒，未测过时为 0
+    pub single_stream_throughput: f64,
+    pub striped_throughput: f64,
+    // 不压缩的数据由内核从页缓存直接发到套接字，不复制成帧
+    pub sendfile: bool,
+}
+
+// 正在进行的传输的链路参数
+#[derive(Clone, Default)]
+pub struct DiagnosticsRegistry {
+    links: Arc<Mutex<HashMap<String, LinkDiagnostics>>>,
+}
+
+impl DiagnosticsRegistry {
+    pub fn list(&self) -> Vec<LinkDiagnostics> {
+        self.lock().values().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, LinkDiagnostics>> {
+        self.links.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// 根据测得的吞吐量和往返时间调整块大小和写入缓冲区，
+// 参数变化时更新诊断信息并通知界面
+pub struct LinkTuning {
+    app_handle: tauri::AppHandle,
+    registry: DiagnosticsRegistry,
+    diagnostics: LinkDiagnostics,
+    sending: bool,
+    max_chunk: usize,
+    rtt: Duration,
+    sample_interval: Duration,
+    sample_start: Instant,
+    sample_bytes: u64,
+    // 正在拆分到多个连接传输，采样计入 striped_throughput
+    striped: bool,
+}
+
+impl LinkTuning {
+    pub fn new(
+        app_handle: &tauri::AppHandle,
+        registry: &DiagnosticsRegistry,
+        transfer_id: &str,
+        direction: &str,
+        stream: &TcpStream,
+        max_chunk: usize,
+        rtt: Duration,
+    ) -> Self {
+        let socket = SockRef::from(stream);
+        let max_chunk = max_chunk.max(1);
+        let sending = direction == "send";
+        let diagnostics = LinkDiagnostics {
+            transfer_id: transfer_id.to_string(),
+            direction: direction.to_string(),
+            peer: stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            rtt_ms: rtt.as_secs_f64() * 1000.0,
+            throughput: 0.0,
+            bdp_bytes: 0,
+            chunk_size: if sending {
+                INITIAL_CHUNK_SIZE.clamp(MIN_CHUNK_SIZE.min(max_chunk), max_chunk)
+            } else {
+                max_chunk
+            },
+            write_buffer: if sending { 0 } else { INITIAL_WRITE_BUFFER },
+            send_buffer: socket.send_buffer_size().unwrap_or(0),
+            recv_buffer: socket.recv_buffer_size().unwrap_or(0),
+            nodelay: stream.nodelay().unwrap_or(false),
+            streams: 1,
+            single_stream_throughput: 0.0,
+            striped_throughput: 0.0,
+            sendfile: false,
+        };
+
+        Self {
+            app_handle: app_handle.clone(),
+            registry: registry.clone(),
+            diagnostics,
+            sending,
+            max_chunk,
+            rtt,
+            sample_interval: SAMPLE_INTERVAL.max(rtt * SAMPLE_RTTS),
+            sample_start: Instant::now(),
+            sample_bytes: 0,
+            striped: false,
+        }
+    }
+
+    // 会话确定后补充并行连接数和发送方式，并发布初始参数
+    pub fn describe(&mut self, streams: usize, sendfile: bool) {
+        self.diagnostics.streams = streams.max(1);
+        self.diagnostics.sendfile = sendfile;
+        self.publish();
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.diagnostics.chunk_size
+    }
+
+    pub fn write_buffer(&self) -> usize {
+        self.diagnostics.write_buffer
+    }
+
+    // 大文件默认拆分到多个连接，两种方式都测过且拆分反而更慢时才改用单条连接，
+    // 例如瓶颈在无线网络或磁盘时多条连接只会互相争抢
+    pub fn wants_stripes(&self) -> bool {
+        let single = self.diagnostics.single_stream_throughput;
+        let striped = self.diagnostics.striped_throughput;
+        !(single > 0.0 && striped > 0.0 && striped < single)
+    }
+
+    // 开始或结束拆分传输，之前未满一个周期的采样丢弃，不混入另一种方式
+    pub fn set_striped(&mut self, striped: bool) {
+        if striped != self.striped {
+            self.striped = striped;
+            self.sample_start = Instant::now();
+            self.sample_bytes = 0;
+        }
+    }
+
+    // 记录发送或接收的字节，每个采样周期重新计算一次
+    pub fn record(&mut self, bytes: u64) {
+        self.sample_bytes += bytes;
+        let elapsed = self.sample_start.elapsed();
+        if elapsed < self.sample_interval {
+            return;
+        }
+
+        let sample_bytes = std::mem::take(&mut self.sample_bytes);
+        self.sample_start = Instant::now();
+        if elapsed > self.sample_interval * STALE_SAMPLES {
+            return;
+        }
+
+        let throughput = sample_bytes as f64 / elapsed.as_secs_f64();
+        self.diagnostics.throughput = throughput;
+        let mode = if self.striped {
+            &mut self.diagnostics.striped_throughput
+        } else {
+            &mut self.diagnostics.single_stream_throughput
+        };
+        *mode = if *mode > 0.0 {
+            *mode + (throughput - *mode) * THROUGHPUT_SMOOTHING
+        } else {
+            throughput
+        };
+        self.diagnostics.bdp_bytes = (throughput * self.rtt.as_secs_f64()) as u64;
+
+        // 发送方决定块大小，接收方只决定写入磁盘的缓冲区
+        let (current, target, min, max) = if self.sending {
+            (
+                self.diagnostics.chunk_size,
+                throughput * FRAME_TIME.as_secs_f64(),
+                MIN_CHUNK_SIZE.min(self.max_chunk),
+                self.max_chunk,
+            )
+        } else {
+            (
+                self.diagnostics.write_buffer,
+                throughput * FLUSH_TIME.as_secs_f64(),
+                MIN_WRITE_BUFFER,
+                MAX_WRITE_BUFFER,
+            )
+        };
+        let size = Self::resize(current, target).clamp(min, max);
+        if size != current {
+            if self.sending {
+                self.diagnostics.chunk_size = size;
+            } else {
+                self.diagnostics.write_buffer = size;
+            }
+            log::info!(
+                "Transfer {} tuned to {} byte {} at {:.1} MB/s",
+                self.diagnostics.transfer_id,
+                size,
+                if self.sending {
+                    "chunks"
+                } else {
+                    "write buffer"
+                },
+                throughput / 1_000_000.0
+            );
+            self.publish();
+        } else {
+            // 吞吐量仍然更新，只是不通知界面
+            self.registry.lock().insert(
+                self.diagnostics.transfer_id.clone(),
+                self.diagnostics.clone(),
+            );
+        }
+    }
+
+    // 目标大小与当前大小相差不到一倍时保持不变，否则取 2 的幂，避免在边界附近来回变化
+    fn resize(current: usize, target: f64) -> usize {
+        let current_size = current as f64;
+        if target >= current_size / 2.0 && target <= current_size * 2.0 {
+            return current;
+        }
+        (target as usize).max(1).next_power_of_two()
+    }
+
+    fn publish(&self) {
+        self.registry.lock().insert(
+            self.diagnostics.transfer_id.clone(),
+            self.diagnostics.clone(),
+        );
+        let _ = self
+            .app_handle
+            .emit("transfer-diagnostics", &self.diagnostics);
+    }
+}
+
+impl Drop for LinkTuning {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.diagnostics.transfer_id);
+    }
+}
@@ -19,7 +19,11 @@ import {
   Globe,
   Activity,
   Sun,
-  Moon
+  Moon,
+  Pause,
+  Play,
+  ChevronUp,
+  ChevronDown
 } from 'lucide-react';
 import { PerformanceMonitor } from './components/PerformanceMonitor';
 import { i18nManager, Language, getTranslations } from './i18n';
@@ -33,9 +37,52 @@ interface Device {
 }
 
 interface TransferProgress {
+  transfer_id: string;
+  peer_id: string;
   file_name: string;
+  file_index: number;
+  file_count: number;
   progress: number;
-  status: 'sending' | 'receiving' | 'completed' | 'failed';
+  status: 'hashing' | 'sending' | 'receiving' | 'completed' | 'skipped' | 'identical' | 'failed' | 'checksum_mismatch' | 'refused' | 'cancelled';
+  bytes_done: number;
+  bytes_total: number;
+  speed: number;
+  average_speed: number;
+  eta_secs: number | null;
+  saved_path?: string;
+}
+
+interface IncomingTransferRequest {
+  transfer_id: string;
+  sender: Device;
+  verified: boolean;
+  file_count: number;
+  total_size: number;
+}
+
+type TransferState = 'queued' | 'running' | 'paused' | 'cancelled';
+
+interface ActiveTransfer {
+  transfer_id: string;
+  direction: 'send' | 'receive';
+  peer: Device;
+  file_count: number;
+  total_size: number;
+  state: TransferState;
+}
+
+interface ScheduledTransfer {
+  transfer_id: string;
+  peer: Device;
+  file_paths: string[];
+  priority: 'low' | 'normal' | 'high';
+  status: 'queued' | 'running';
+  state: TransferState;
+}
+
+interface TransferError {
+  peer: string;
+  message: string;
 }
 
 function App() {
@@ -43,6 +90,7 @@ function App() {
   const [isScanning, setIsScanning] = useState(false);
   const [selectedFiles, setSelectedFiles] = useState<string[]>([]);
   const [transferProgress, setTransferProgress] = useState<TransferProgress[]>([]);
+  const [activeTransfers, setActiveTransfers] = useState<ActiveTransfer[]>([]);
   const [myDeviceInfo, setMyDeviceInfo] = useState<Device | null>(null);
   const [isDragOver, setIsDragOver] = useState(false);
   const [darkMode, setDarkMode] = useState(false);
@@ -68,14 +116,74 @@ function App() {
     const unlistenProgress = listen('transfer-progress', (event) => {
       const progress = event.payload as TransferProgress;
       setTransferProgress(prev => {
-        const exists = prev.find(p => p.file_name === progress.file_name);
-        if (exists) {
-          return prev.map(p => p.file_name === progress.file_name ? progress : p);
+        const same = (p: TransferProgress) =>
+          p.transfer_id === progress.transfer_id && p.file_name === progress.file_name;
+        if (prev.find(same)) {
+          return prev.map(p => same(p) ? progress : p);
         }
         return [...prev, progress];
       });
     });
 
+    // 监听传输排队、开始、暂停/继续和结束，用于显示控制按钮
+    const unlistenQueued = listen('transfer-queued', (event) => {
+      const queued = event.payload as ScheduledTransfer;
+      setActiveTransfers(prev => [...prev, toActiveTransfer(queued)]);
+    });
+
+    // 上次退出时未完成的发送任务会重新排队
+    invoke<ScheduledTransfer[]>('list_transfers')
+      .then(transfers => setActiveTransfers(transfers.map(toActiveTransfer)))
+      .catch(error => console.error('Failed to list transfers:', error));
+
+    const unlistenStarted = listen('transfer-started', (event) => {
+      const transfer = event.payload as Omit<ActiveTransfer, 'state'>;
+      setActiveTransfers(prev => [
+        ...prev.filter(t => t.transfer_id !== transfer.transfer_id),
+        { ...transfer, state: 'running' }
+      ]);
+    });
+
+    const unlistenState = listen('transfer-state', (event) => {
+      const { transfer_id, state } = event.payload as { transfer_id: string; state: TransferState };
+      setActiveTransfers(prev => prev.map(t => {
+        if (t.transfer_id !== transfer_id) return t;
+        // 排队中的任务继续后仍在排队
+        return { ...t, state: t.state === 'queued' && state === 'running' ? 'queued' : state };
+      }));
+    });
+
+    const unlistenFinished = listen('transfer-finished', (event) => {
+      const { transfer_id } = event.payload as { transfer_id: string; status: string };
+      setActiveTransfers(prev => prev.filter(t => t.transfer_id !== transfer_id));
+    });
+
+    // 监听传输错误事件（例如双方版本不兼容）
+    const unlistenError = listen('transfer-error', (event) => {
+      const error = event.payload as TransferError;
+      console.error('Transfer error:', error);
+      alert(`${error.peer}: ${error.message}`);
+    });
+
+    // 监听传输请求，由用户决定是否接收
+    const unlistenIncoming = listen('incoming-transfer-request', async (event) => {
+      const request = event.payload as IncomingTransferRequest;
+      const sizeMb = (request.total_size / 1024 / 1024).toFixed(2);
+      const warning = request.verified ? '' : '\n注意：无法确认发送方身份';
+      const accept = window.confirm(
+        `${request.sender.name} (${request.sender.ip}) 想要发送 ${request.file_count} 个项目，共 ${sizeMb} MB，是否接收？${warning}`
+      );
+      try {
+        await invoke('respond_to_transfer', {
+          transferId: request.transfer_id,
+          accept,
+          saveDir: null
+        });
+      } catch (error) {
+        console.error('Failed to respond to transfer:', error);
+      }
+    });
+
     // 获取本设备信息
     initializeDevice();
     
@@ -104,12 +212,55 @@ function App() {
     return () => {
       unlisten.then(f => f());
       unlistenProgress.then(f => f());
+      unlistenError.then(f => f());
+      unlistenIncoming.then(f => f());
+      unlistenQueued.then(f => f());
+      unlistenStarted.then(f => f());
+      unlistenState.then(f => f());
+      unlistenFinished.then(f => f());
       trayListenersPromise.then(listeners => {
         listeners.forEach(unlisten => unlisten());
       });
     };
   }, []);
 
+  const toActiveTransfer = (transfer: ScheduledTransfer): ActiveTransfer => ({
+    transfer_id: transfer.transfer_id,
+    direction: 'send',
+    peer: transfer.peer,
+    file_count: transfer.file_paths.length,
+    total_size: 0,
+    state: transfer.status === 'queued' && transfer.state === 'running' ? 'queued' : transfer.state
+  });
+
+  // 在发送队列中上移或下移一位
+  const moveInQueue = async (transferId: string, offset: number) => {
+    try {
+      const queue = await invoke<ScheduledTransfer[]>('list_queue');
+      const index = queue.findIndex(t => t.transfer_id === transferId);
+      if (index < 0 || index + offset < 0) return;
+      await invoke('reorder_queue', { transferId, position: index + offset, priority: null });
+      const reordered = await invoke<ScheduledTransfer[]>('list_queue');
+      const order = reordered.map(t => t.transfer_id);
+      setActiveTransfers(prev => [...prev].sort((a, b) => {
+        const ia = order.indexOf(a.transfer_id);
+        const ib = order.indexOf(b.transfer_id);
+        if (ia < 0 || ib < 0) return 0;
+        return ia - ib;
+      }));
+    } catch (error) {
+      console.error('Failed to reorder queue:', error);
+    }
+  };
+
+  const controlTransfer = async (transferId: string, command: 'pause_transfer' | 'resume_transfer' | 'cancel_transfer') => {
+    try {
+      await invoke(command, { transferId });
+    } catch (error) {
+      console.error(`Failed to ${command}:`, error);
+    }
+  };
+
   const initializeDevice = async () => {
     try {
       const deviceInfo = await invoke<Device>('get_device_info');
@@ -228,11 +379,32 @@ function App() {
     }
   };
 
+  const formatSpeed = (bytesPerSec: number) => {
+    if (bytesPerSec >= 1024 * 1024) {
+      return `${(bytesPerSec / 1024 / 1024).toFixed(1)} MB/s`;
+    }
+    return `${(bytesPerSec / 1024).toFixed(0)} KB/s`;
+  };
+
+  const formatEta = (secs: number) => {
+    const total = Math.ceil(secs);
+    if (total >= 3600) {
+      return `${Math.floor(total / 3600)} 小时 ${Math.floor((total % 3600) / 60)} 分`;
+    }
+    if (total >= 60) {
+      return `${Math.floor(total / 60)} 分 ${total % 60} 秒`;
+    }
+    return `${total} 秒`;
+  };
+
   const getStatusColor = (status: string) => {
     switch (status) {
       case 'completed':
+      case 'identical':
         return 'text-green-600';
       case 'failed':
+      case 'checksum_mismatch':
+      case 'refused':
         return 'text-red-600';
       case 'sending':
       case 'receiving':
@@ -245,8 +417,11 @@ function App() {
   const getStatusIcon = (status: string) => {
     switch (status) {
       case 'completed':
+      case 'identical':
         return <Check className="w-4 h-4" />;
       case 'failed':
+      case 'checksum_mismatch':
+      case 'refused':
         return <X className="w-4 h-4" />;
       case 'sending':
         return <Upload className="w-4 h-4" />;
@@ -522,6 +697,78 @@ function App() {
           </div>
         </div>
 
+        {/* 正在进行的传输 */}
+        {activeTransfers.length > 0 && (
+          <div className="bg-white rounded-xl shadow-lg p-6 mb-6">
+            <h2 className="text-lg font-semibold text-gray-800 mb-4 flex items-center">
+              <Activity className="w-5 h-5 mr-2 text-blue-500" />
+              正在传输
+            </h2>
+
+            <div className="space-y-3">
+              {activeTransfers.map(transfer => (
+                <div key={transfer.transfer_id} className="border rounded-lg p-4 flex items-center justify-between">
+                  <div>
+                    <div className="font-medium text-gray-800">
+                      {transfer.direction === 'send' ? '发送到' : '接收自'} {transfer.peer.name}
+                    </div>
+                    <div className="text-sm text-gray-600">
+                      {transfer.file_count} 个项目
+                      {transfer.total_size > 0 && `，${(transfer.total_size / 1024 / 1024).toFixed(2)} MB`}
+                      {transfer.state === 'queued' && '（排队中）'}
+                      {transfer.state === 'paused' && '（已暂停）'}
+                    </div>
+                  </div>
+                  <div className="flex items-center space-x-2">
+                    {transfer.state === 'queued' && (
+                      <>
+                        <button
+                          onClick={() => moveInQueue(transfer.transfer_id, -1)}
+                          className="p-2 rounded-lg text-gray-600 hover:bg-gray-100"
+                          title="上移"
+                        >
+                          <ChevronUp className="w-4 h-4" />
+                        </button>
+                        <button
+                          onClick={() => moveInQueue(transfer.transfer_id, 1)}
+                          className="p-2 rounded-lg text-gray-600 hover:bg-gray-100"
+                          title="下移"
+                        >
+                          <ChevronDown className="w-4 h-4" />
+                        </button>
+                      </>
+                    )}
+                    {transfer.state === 'paused' ? (
+                      <button
+                        onClick={() => controlTransfer(transfer.transfer_id, 'resume_transfer')}
+                        className="p-2 rounded-lg text-blue-600 hover:bg-blue-50"
+                        title="继续"
+                      >
+                        <Play className="w-4 h-4" />
+                      </button>
+                    ) : (
+                      <button
+                        onClick={() => controlTransfer(transfer.transfer_id, 'pause_transfer')}
+                        className="p-2 rounded-lg text-blue-600 hover:bg-blue-50"
+                        title="暂停"
+                      >
+                        <Pause className="w-4 h-4" />
+                      </button>
+                    )}
+                    <button
+                      onClick={() => controlTransfer(transfer.transfer_id, 'cancel_transfer')}
+                      className="p-2 rounded-lg text-red-600 hover:bg-red-50"
+                      title="取消"
+                    >
+                      <X className="w-4 h-4" />
+                    </button>
+                  </div>
+                </div>
+              ))}
+            </div>
+          </div>
+        )}
+
         {/* 传输进度区域 */}
         {transferProgress.length > 0 && (
           <div className="bg-white rounded-xl shadow-lg p-6">
@@ -548,8 +795,16 @@ function App() {
                     />
                   </div>
                   
-                  <div className="text-right text-sm text-gray-600 mt-1">
-                    {progress.progress.toFixed(1)}%
+                  <div className="flex justify-between text-sm text-gray-600 mt-1">
+                    <span>
+                      {(progress.status === 'sending' || progress.status === 'receiving') && (
+                        <>
+                          {formatSpeed(progress.speed)}
+                          {progress.eta_secs !== null && `，剩余 ${formatEta(progress.eta_secs)}`}
+                        </>
+                      )}
+                    </span>
+                    <span>{progress.progress.toFixed(1)}%</span>
                   </div>
                 </div>
               ))}